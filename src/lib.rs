@@ -0,0 +1,825 @@
+use core::time::Duration;
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioIo};
+use lildb::{
+    lil_db_shell_client::LilDbShellClient, ConnectRequest, ConnectResponse, DisconnectRequest,
+    DisconnectResponse,
+};
+use lildb::{CommandRequest, CommandResponse};
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    fs,
+    future::Future,
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::OnceCell,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{
+    codec::CompressionEncoding,
+    codegen::{http::Uri, Service},
+    metadata::{Ascii, MetadataValue},
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity},
+    Code, Request, Status,
+};
+use tracing::{debug, info, warn};
+
+pub mod lildb {
+    tonic::include_proto!("lildb");
+}
+
+#[derive(Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Compression, String> {
+        match value {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("unknown compression {}", value)),
+        }
+    }
+}
+
+/// How to reach a server: transport security, credentials and retry policy.
+pub struct ConnectOptions {
+    pub tls: bool,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds, doubled after each attempt.
+    pub retry_delay: u64,
+    /// Spread each retry delay by up to a quarter either way, so clients that
+    /// lost the same server don't all retry in step.
+    pub retry_jitter: bool,
+    /// Per-attempt connect timeout in milliseconds.
+    pub connect_timeout: u64,
+    pub token: Option<String>,
+    pub compression: Compression,
+    /// Seconds between HTTP/2 keepalive pings; none are sent when unset.
+    pub keepalive_interval: Option<u64>,
+    /// Seconds to wait for a keepalive ack before dropping the connection.
+    pub keepalive_timeout: u64,
+    pub keepalive: bool,
+    /// Local address outgoing connections are made from.
+    pub bind: Option<IpAddr>,
+    /// Accept any server certificate. Only meant for testing against
+    /// self-signed servers.
+    pub insecure: bool,
+    /// Charset used to decode raw output bytes from the server.
+    pub encoding: &'static Encoding,
+    /// Longest command sent, in bytes; longer ones fail before reaching the
+    /// server.
+    pub max_command_bytes: usize,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> ConnectOptions {
+        ConnectOptions {
+            tls: false,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            retries: 3,
+            retry_delay: 500,
+            retry_jitter: true,
+            connect_timeout: 10_000,
+            token: None,
+            compression: Compression::None,
+            keepalive_interval: None,
+            keepalive_timeout: 30,
+            keepalive: true,
+            bind: None,
+            insecure: false,
+            encoding: UTF_8,
+            max_command_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+type Client = LilDbShellClient<InterceptedService<Channel, Auth>>;
+
+#[derive(Clone)]
+struct Auth {
+    token: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for Auth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.token {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+        }
+
+        Ok(request)
+    }
+}
+
+/// The IP reported to the server, resolved on first use and then cached.
+///
+/// Unless an IP is given or lookups are turned off, resolving it makes an
+/// HTTPS request to the third-party service api.ipify.org.
+pub struct PublicIp {
+    explicit: Option<String>,
+    address: String,
+    lookup: bool,
+    resolved: OnceCell<String>,
+}
+
+impl PublicIp {
+    /// Uses `explicit` when given, otherwise looks the IP up, falling back to
+    /// the local address of the route to `address`.
+    pub fn new(explicit: Option<String>, address: &str) -> PublicIp {
+        PublicIp {
+            explicit,
+            address: address.to_string(),
+            lookup: true,
+            resolved: OnceCell::new(),
+        }
+    }
+
+    /// Like `new`, but never contacts the lookup service; without an explicit
+    /// IP the local address of the route to `address` is sent.
+    pub fn offline(explicit: Option<String>, address: &str) -> PublicIp {
+        PublicIp {
+            lookup: false,
+            ..PublicIp::new(explicit, address)
+        }
+    }
+
+    pub async fn get(&self) -> &str {
+        self.resolved
+            .get_or_init(|| async {
+                match &self.explicit {
+                    Some(ip) => ip.to_string(),
+                    None if self.lookup => lookup_public_ip(&self.address).await,
+                    None => local_ip(&self.address).await.unwrap_or_default(),
+                }
+            })
+            .await
+    }
+}
+
+/// An open session with a LilDB server. Clones share the underlying channel.
+#[derive(Clone)]
+pub struct LilDbConnection {
+    client: Client,
+    public_ip: Arc<PublicIp>,
+    encoding: &'static Encoding,
+    max_command_bytes: usize,
+    /// Whether commands are sent gzip-compressed; cleared if the server
+    /// turns out not to accept it.
+    compress: bool,
+}
+
+impl LilDbConnection {
+    /// Connects to `address` and sends the connect request, retrying with
+    /// backoff while the server is unreachable or unavailable.
+    ///
+    /// The returned response says whether the server accepted the session.
+    pub async fn connect(
+        address: &str,
+        public_ip: Arc<PublicIp>,
+        options: &ConnectOptions,
+    ) -> Result<(LilDbConnection, ConnectResponse), Box<dyn Error>> {
+        let (client, response): (Client, ConnectResponse) =
+            connect_to_db(options, address, &public_ip).await?;
+
+        Ok((
+            LilDbConnection {
+                client,
+                public_ip,
+                encoding: options.encoding,
+                max_command_bytes: options.max_command_bytes,
+                compress: matches!(options.compression, Compression::Gzip),
+            },
+            response,
+        ))
+    }
+
+    /// Sends `command` and yields each output chunk as the server streams it.
+    #[allow(clippy::result_large_err)] // Status is what tonic hands back
+    pub async fn run_command(
+        &mut self,
+        command: &str,
+    ) -> Result<impl Stream<Item = Result<String, Status>> + Unpin, Status> {
+        if command.len() > self.max_command_bytes {
+            return Err(Status::out_of_range(format!(
+                "command is {} bytes, over the {} byte limit set by --max-command-bytes",
+                command.len(),
+                self.max_command_bytes
+            )));
+        }
+
+        debug!(
+            "Sending command ({} bytes): {}",
+            command.len(),
+            preview(command)
+        );
+
+        let request = || {
+            tokio_stream::once(CommandRequest {
+                command: command.to_string(),
+            })
+        };
+
+        let mut client: Client = self.client.clone();
+
+        if self.compress {
+            client = client.send_compressed(CompressionEncoding::Gzip);
+        }
+
+        // The server refuses a compressed request before running it, so the
+        // command is safe to send again uncompressed.
+        let inbound = match client.run_command(request()).await {
+            Err(status) if self.compress && rejects_gzip(&status) => {
+                warn!("The server does not accept gzip requests, sending them uncompressed");
+
+                self.compress = false;
+
+                self.client.run_command(request()).await?
+            }
+            response => response?,
+        }
+        .into_inner();
+
+        Ok(Decoded {
+            inbound,
+            decoder: Some(self.encoding.new_decoder()),
+        })
+    }
+
+    /// Sends an empty command and waits for the server to close the reply
+    /// stream, returning the round trip time.
+    pub async fn ping(&mut self) -> Result<Duration, Status> {
+        let start: Instant = Instant::now();
+
+        let mut inbound = self.run_command("").await?;
+
+        while inbound.next().await.transpose()?.is_some() {}
+
+        Ok(start.elapsed())
+    }
+
+    pub async fn disconnect(&mut self) -> Result<DisconnectResponse, Status> {
+        Ok(self
+            .client
+            .disconnect_from_db(DisconnectRequest {
+                ip: self.public_ip.get().await.to_string(),
+            })
+            .await?
+            .into_inner())
+    }
+}
+
+// The reply to one command. A single decoder covers the whole reply, so
+// characters split across chunks still come out whole, and it is flushed at
+// the end so a sequence the server cut short becomes U+FFFD instead of being
+// dropped.
+struct Decoded<S> {
+    inbound: S,
+    // None once the reply has ended.
+    decoder: Option<Decoder>,
+}
+
+impl<S> Stream for Decoded<S>
+where
+    S: Stream<Item = Result<CommandResponse, Status>> + Unpin,
+{
+    type Item = Result<String, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this: &mut Decoded<S> = &mut self;
+
+        loop {
+            let Some(decoder) = this.decoder.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            let Some(res) = std::task::ready!(Pin::new(&mut this.inbound).poll_next(cx)) else {
+                let rest: String = decode(decoder, &[], true);
+
+                this.decoder = None;
+
+                // An empty chunk would read as the server closing the session.
+                return Poll::Ready((!rest.is_empty()).then_some(Ok(rest)));
+            };
+
+            let res: CommandResponse = match res {
+                Ok(res) => res,
+                Err(status) => return Poll::Ready(Some(Err(status))),
+            };
+
+            let output: String = if res.raw_output.is_empty() {
+                res.output
+            } else {
+                decode(decoder, &res.raw_output, false)
+            };
+
+            // Bytes that only start a character decode to nothing until the
+            // rest arrives; passing that on would also look like a close.
+            if output.is_empty() && !res.raw_output.is_empty() {
+                continue;
+            }
+
+            debug!(
+                "Received chunk ({} bytes): {}",
+                output.len(),
+                preview(&output)
+            );
+
+            return Poll::Ready(Some(Ok(output)));
+        }
+    }
+}
+
+fn decode(decoder: &mut Decoder, bytes: &[u8], last: bool) -> String {
+    let mut output: String = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length(bytes.len())
+            .unwrap_or_default(),
+    );
+
+    let _ = decoder.decode_to_string(bytes, &mut output, last);
+
+    output
+}
+
+// A server that cannot decompress a request answers Unimplemented and lists
+// the encodings it does take in grpc-accept-encoding.
+fn rejects_gzip(status: &Status) -> bool {
+    status.code() == Code::Unimplemented
+        && status
+            .metadata()
+            .get("grpc-accept-encoding")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| !value.split(',').any(|encoding| encoding.trim() == "gzip"))
+}
+
+// Field tag and length prefix around the command in an encoded CommandRequest.
+const MESSAGE_OVERHEAD: usize = 16;
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+const PREVIEW_CHARS: usize = 200;
+
+// Scales `delay` by a random factor between 0.75 and 1.25. A fresh
+// RandomState is seeded randomly, which is all the randomness this needs.
+fn jittered(delay: Duration) -> Duration {
+    let random: u64 = RandomState::new().hash_one(());
+    let fraction: f64 = random as f64 / u64::MAX as f64;
+
+    delay.mul_f64(0.75 + fraction / 2.0)
+}
+
+// Shortens logged payloads so a large result doesn't flood the log.
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{:?}...", &text[..end]),
+        None => format!("{:?}", text),
+    }
+}
+
+fn tls_config(options: &ConnectOptions) -> Result<ClientTlsConfig, Box<dyn Error>> {
+    let mut tls: ClientTlsConfig = ClientTlsConfig::new();
+
+    match &options.ca_cert {
+        Some(path) => tls = tls.ca_certificate(Certificate::from_pem(fs::read(path)?)),
+        None => tls = tls.with_native_roots(),
+    }
+
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => {
+            tls = tls.identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+        }
+        (None, None) => {}
+        _ => return Err("--client-cert and --client-key must be given together".into()),
+    }
+
+    Ok(tls)
+}
+
+// Accepts every certificate but still checks handshake signatures, so the
+// session is encrypted even though the server's identity is unknown.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn insecure_tls_config(options: &ConnectOptions) -> Result<ClientConfig, Box<dyn Error>> {
+    let provider: Arc<CryptoProvider> = Arc::new(crypto::ring::default_provider());
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)));
+
+    let mut config: ClientConfig = match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(
+            CertificateDer::pem_slice_iter(&fs::read(cert)?).collect::<Result<_, _>>()?,
+            PrivateKeyDer::from_pem_slice(&fs::read(key)?)?,
+        )?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("--client-cert and --client-key must be given together".into()),
+    };
+
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(config)
+}
+
+// Does the TLS handshake itself, as tonic's own TLS layer always verifies the
+// server certificate. The channel is given an http:// URL so tonic leaves the
+// stream alone.
+#[derive(Clone)]
+struct InsecureConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+    domain: ServerName<'static>,
+}
+
+impl Service<Uri> for InsecureConnector {
+    type Response = TokioIo<TlsStream<TcpStream>>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut connector: InsecureConnector = self.clone();
+
+        Box::pin(async move {
+            let tcp: TcpStream = connector.http.call(uri).await?.into_inner();
+            let tls: TlsStream<TcpStream> = connector.tls.connect(connector.domain, tcp).await?;
+
+            Ok(TokioIo::new(tls))
+        })
+    }
+}
+
+async fn local_ip(address: &str) -> Option<String> {
+    let uri: Uri = server_url(address, false).ok()?.parse().ok()?;
+    let target: String = format!("{}:{}", uri.host()?, uri.port_u16().unwrap_or(80));
+
+    let remote: SocketAddr = tokio::net::lookup_host(target).await.ok()?.next()?;
+    let local: &str = if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+
+    let socket: UdpSocket = UdpSocket::bind(local).await.ok()?;
+    socket.connect(remote).await.ok()?;
+
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+async fn lookup_public_ip(address: &str) -> String {
+    let result: Result<String, reqwest::Error> = async {
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?
+            .get("https://api.ipify.org")
+            .send()
+            .await?
+            .text()
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(ip) => ip,
+        Err(e) => {
+            let fallback: String = local_ip(address).await.unwrap_or_default();
+
+            warn!(
+                "Could not look up the public IP, sending {:?} instead: {}",
+                fallback, e
+            );
+
+            fallback
+        }
+    }
+}
+
+fn server_url(address: &str, tls: bool) -> Result<String, String> {
+    let url: String = match address.split_once("://") {
+        Some(("http", _)) if tls => {
+            return Err("--tls cannot be used with an http:// address".to_string())
+        }
+        Some(("http" | "https", _)) => address.to_string(),
+        Some((scheme, _)) => {
+            return Err(format!(
+                "unsupported scheme {}://, use http:// or https://",
+                scheme
+            ))
+        }
+        None if tls => format!("https://{}", address),
+        None => format!("http://{}", address),
+    };
+
+    match url.parse::<Uri>() {
+        Ok(uri) if uri.host().is_some() => Ok(url),
+        _ => Err(format!(
+            "invalid address {}, expected host:port such as localhost:50051",
+            address
+        )),
+    }
+}
+
+async fn connect_to_db(
+    options: &ConnectOptions,
+    address: &str,
+    public_ip: &PublicIp,
+) -> Result<(Client, ConnectResponse), Box<dyn Error>> {
+    let token: Option<MetadataValue<Ascii>> = match &options.token {
+        Some(token) => Some(
+            format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| "the token contains characters not allowed in a header")?,
+        ),
+        None => None,
+    };
+
+    let url: String = server_url(address, options.tls)?;
+    let tls: bool = url.starts_with("https://");
+
+    if options.insecure && !tls {
+        return Err("--insecure can only be used with --tls or an https:// address".into());
+    }
+
+    let insecure: Option<(TlsConnector, ServerName<'static>)> = if options.insecure {
+        let uri: Uri = url.parse()?;
+        let host: &str = uri.host().unwrap_or_default().trim_matches(['[', ']']);
+
+        Some((
+            TlsConnector::from(Arc::new(insecure_tls_config(options)?)),
+            ServerName::try_from(host.to_string())?,
+        ))
+    } else {
+        None
+    };
+
+    let url: String = if options.insecure {
+        url.replacen("https://", "http://", 1)
+    } else {
+        url
+    };
+
+    let mut endpoint: Endpoint =
+        Channel::from_shared(url)?.connect_timeout(Duration::from_millis(options.connect_timeout));
+
+    if options.keepalive {
+        endpoint = endpoint
+            .keep_alive_while_idle(true)
+            .keep_alive_timeout(Duration::from_secs(options.keepalive_timeout));
+
+        if let Some(interval) = options.keepalive_interval {
+            endpoint = endpoint.http2_keep_alive_interval(Duration::from_secs(interval));
+        }
+    }
+
+    if tls && !options.insecure {
+        endpoint = endpoint.tls_config(tls_config(options)?)?;
+    }
+
+    // Binding once up front turns a bad --bind into a clear error instead of
+    // a transport error on every attempt.
+    if let Some(ip) = options.bind {
+        TcpListener::bind(SocketAddr::new(ip, 0))
+            .map_err(|e| format!("cannot bind to local address {}: {}", ip, e))?;
+    }
+
+    let start: Instant = Instant::now();
+    let mut delay: Duration = Duration::from_millis(options.retry_delay);
+
+    for attempt in 0..=options.retries {
+        info!("Attempting to connect to {}...", address);
+
+        let mut http: HttpConnector = HttpConnector::new();
+
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        http.set_local_address(options.bind);
+
+        let connection: Result<Channel, tonic::transport::Error> = match &insecure {
+            Some((tls, domain)) => {
+                let connector: InsecureConnector = InsecureConnector {
+                    http,
+                    tls: tls.clone(),
+                    domain: domain.clone(),
+                };
+
+                endpoint.connect_with_connector(connector).await
+            }
+            _ if options.bind.is_some() => endpoint.connect_with_connector(http).await,
+            _ => endpoint.connect().await,
+        };
+
+        match connection {
+            Ok(channel) => {
+                info!(
+                    "Successfully connected to {} in {:?}",
+                    address,
+                    start.elapsed()
+                );
+
+                let mut client: Client = LilDbShellClient::with_interceptor(
+                    channel,
+                    Auth {
+                        token: token.clone(),
+                    },
+                );
+
+                // The check in run_command gives the clearer error; this keeps
+                // tonic from encoding anything past it regardless.
+                client = client.max_encoding_message_size(
+                    options.max_command_bytes.saturating_add(MESSAGE_OVERHEAD),
+                );
+
+                // Replies may come back compressed, but the connect request
+                // goes out plain so a server without gzip can still accept
+                // it; run_command compresses commands once connected.
+                if let Compression::Gzip = options.compression {
+                    client = client.accept_compressed(CompressionEncoding::Gzip);
+                }
+
+                let request: ConnectRequest = ConnectRequest {
+                    ip: public_ip.get().await.to_string(),
+                };
+
+                // Only a busy or restarting server is worth retrying; anything
+                // else, such as a rejected token, fails the same way every time.
+                match client.connect_to_db(request).await {
+                    Ok(response) => return Ok((client, response.into_inner())),
+                    Err(status) if status.code() == Code::Unavailable => {
+                        warn!(
+                            "Connect request {} failed: {}",
+                            attempt + 1,
+                            status.message()
+                        );
+                    }
+                    Err(status) => return Err(status.into()),
+                }
+            }
+            Err(e) => warn!("Connection attempt {} failed: {}", attempt + 1, e),
+        }
+
+        if attempt < options.retries {
+            let wait: Duration = if options.retry_jitter {
+                jittered(delay)
+            } else {
+                delay
+            };
+
+            tokio::time::sleep(wait).await;
+
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    Err(format!(
+        "could not connect to {} after {} attempts ({:?})",
+        address,
+        options.retries + 1,
+        start.elapsed()
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unimplemented(accepted: &'static str) -> Status {
+        let mut status: Status = Status::unimplemented("Content is compressed with `gzip`");
+
+        status
+            .metadata_mut()
+            .insert("grpc-accept-encoding", MetadataValue::from_static(accepted));
+
+        status
+    }
+
+    #[test]
+    fn gzip_rejection_is_recognized() {
+        assert!(rejects_gzip(&unimplemented("identity")));
+        assert!(!rejects_gzip(&unimplemented("identity, gzip")));
+        assert!(!rejects_gzip(&Status::unimplemented("no such method")));
+        assert!(!rejects_gzip(&Status::unavailable("down")));
+    }
+
+    #[tokio::test]
+    async fn truncated_sequence_is_replaced_at_the_end() {
+        let replies: Vec<Result<CommandResponse, Status>> = vec![Ok(CommandResponse {
+            output: String::new(),
+            raw_output: vec![b'a', 0xE2, 0x82],
+        })];
+
+        let decoded: Decoded<_> = Decoded {
+            inbound: tokio_stream::iter(replies),
+            decoder: Some(UTF_8.new_decoder()),
+        };
+
+        let chunks: Vec<String> = decoded.map(Result::unwrap).collect().await;
+
+        assert_eq!(chunks, ["a", "\u{FFFD}"]);
+    }
+
+    #[tokio::test]
+    async fn character_split_across_chunks_is_joined() {
+        let replies: Vec<Result<CommandResponse, Status>> = vec![
+            Ok(CommandResponse {
+                output: String::new(),
+                raw_output: vec![0xE2],
+            }),
+            Ok(CommandResponse {
+                output: String::new(),
+                raw_output: vec![0x82, 0xAC],
+            }),
+        ];
+
+        let decoded: Decoded<_> = Decoded {
+            inbound: tokio_stream::iter(replies),
+            decoder: Some(UTF_8.new_decoder()),
+        };
+
+        let chunks: Vec<String> = decoded.map(Result::unwrap).collect().await;
+
+        assert_eq!(chunks, ["\u{20AC}"]);
+    }
+}
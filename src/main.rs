@@ -1,156 +1,4591 @@
 use core::time::Duration;
-use crossterm::event::{read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
-use crossterm::terminal::enable_raw_mode;
-use lildb::{
-    lil_db_shell_client::LilDbShellClient, ConnectRequest, DisconnectRequest, DisconnectResponse,
+use crossterm::cursor::{MoveLeft, MoveTo, MoveToColumn, MoveUp};
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::event::{DisableBracketedPaste, EnableBracketedPaste};
+use crossterm::style::Stylize;
+use crossterm::terminal::{
+    self, disable_raw_mode, enable_raw_mode, is_raw_mode_enabled, EnterAlternateScreen,
+    LeaveAlternateScreen,
 };
-use lildb::{CommandRequest, CommandResponse};
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::{execute, queue};
+use encoding_rs::Encoding;
+use lildbsh::lildb::{ConnectResponse, DisconnectResponse};
+use lildbsh::{ConnectOptions, LilDbConnection, PublicIp};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
+    env,
     error::Error,
-    io::{stdout, Write},
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{
+        self, stderr, stdout, BufRead, BufReader, BufWriter, ErrorKind, IsTerminal, Stderr, Stdout,
+        Write,
+    },
+    panic,
+    path::{Path, PathBuf},
     process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{transport::Channel, Response, Streaming};
-pub mod lildb {
-    tonic::include_proto!("lildb");
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+use tonic::{Code, Status};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+struct Config {
+    addresses: Vec<String>,
+    history_file: PathBuf,
+    history_dedup_all: bool,
+    history_size: usize,
+    connection: ConnectOptions,
+    file: Option<PathBuf>,
+    continue_on_error: bool,
+    command: Option<String>,
+    no_reconnect: bool,
+    no_color: bool,
+    prompt: String,
+    format: Format,
+    timing: bool,
+    pager: Pager,
+    log_level: Level,
+    public_ip: Option<String>,
+    line_numbers: bool,
+    init: Option<PathBuf>,
+    strict_init: bool,
+    quiet: bool,
+    no_spinner: bool,
+    log_file: Option<PathBuf>,
+    max_output_lines: Option<usize>,
+    output: Option<PathBuf>,
+    no_public_ip: bool,
+    table: bool,
+    table_style: TableStyle,
+    dry_run: bool,
+    file_format: FileFormat,
+    idle_timeout: Option<u64>,
+    no_trim: bool,
+    timeout: Option<u64>,
+    aliases: BTreeMap<String, String>,
+    no_raw: bool,
+    describe_command: String,
+    no_stream: bool,
+    no_history: bool,
+    confirm_destructive: bool,
+    destructive_keywords: Vec<String>,
+    read_only: bool,
+    write_keywords: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Pager {
+    Off,
+    Auto,
+    Always,
+}
+
+impl FromStr for Pager {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Pager, String> {
+        match value {
+            "off" => Ok(Pager::Off),
+            "auto" => Ok(Pager::Auto),
+            "always" => Ok(Pager::Always),
+            _ => Err(format!("unknown pager mode {}", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TableStyle {
+    Ascii,
+    Markdown,
+    Borderless,
+}
+
+impl FromStr for TableStyle {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<TableStyle, String> {
+        match value {
+            "ascii" => Ok(TableStyle::Ascii),
+            "markdown" => Ok(TableStyle::Markdown),
+            "borderless" => Ok(TableStyle::Borderless),
+            _ => Err(format!("unknown table style {}", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FileFormat {
+    Plain,
+    Jsonl,
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<FileFormat, String> {
+        match value {
+            "plain" => Ok(FileFormat::Plain),
+            "jsonl" => Ok(FileFormat::Jsonl),
+            _ => Err(format!("unknown file format {}", value)),
+        }
+    }
+}
+
+/// One line of a JSON Lines script.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptEntry {
+    command: String,
+    /// When set, whether the command must succeed (true) or fail (false).
+    expect_success: Option<bool>,
+}
+
+// Unknown keys are rejected, so a typo such as retry_dealy is reported rather
+// than silently ignored.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    address: Option<String>,
+    history_file: Option<PathBuf>,
+    history_size: Option<usize>,
+    tls: Option<bool>,
+    ca_cert: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    connect_timeout: Option<u64>,
+    max_command_bytes: Option<usize>,
+    prompt: Option<String>,
+    public_ip: Option<String>,
+    alias: Option<BTreeMap<String, String>>,
+    describe_command: Option<String>,
+    destructive_keywords: Option<Vec<String>>,
+    write_keywords: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Format, String> {
+        match value {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}", value)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    command: &'a str,
+    output: &'a str,
+    success: bool,
+    elapsed_ms: u128,
+}
+
+#[derive(Clone, Copy)]
+enum Tone {
+    Output,
+    Error,
+    Info,
+    Prompt,
+}
+
+#[derive(Clone)]
+struct Prompt {
+    template: String,
+    address: String,
+    count: usize,
+}
+
+impl Prompt {
+    fn new(template: &str, address: &str) -> Prompt {
+        Prompt {
+            template: template.to_string(),
+            address: address.to_string(),
+            count: 1,
+        }
+    }
+
+    fn render(&self) -> String {
+        self.template
+            .replace("{address}", &self.address)
+            .replace("{n}", &self.count.to_string())
+    }
+}
+
+// Numbers output lines across a whole reply. Every chunk is shown starting on
+// a line of its own, so each one starts a new numbered line.
+struct LineNumbers {
+    next: usize,
+}
+
+impl LineNumbers {
+    fn new() -> LineNumbers {
+        LineNumbers { next: 1 }
+    }
+
+    fn number(&mut self, chunk: &str) -> String {
+        let mut numbered: String = String::with_capacity(chunk.len());
+
+        for line in chunk.split_inclusive('\n') {
+            numbered.push_str(&format!("{:>4}  ", self.next));
+            numbered.push_str(line);
+
+            self.next += 1;
+        }
+
+        numbered
+    }
+}
+
+struct Spinner {
+    done: Arc<Notify>,
+    handle: JoinHandle<()>,
+}
+
+impl Spinner {
+    // Drawn after the cursor on the input line and stepped back over, so the
+    // output that follows starts exactly where it would without a spinner.
+    fn start() -> Spinner {
+        let done: Arc<Notify> = Arc::new(Notify::new());
+        let stopped: Arc<Notify> = done.clone();
+
+        let handle: JoinHandle<()> = tokio::spawn(async move {
+            let mut frame: usize = 0;
+
+            loop {
+                let glyph: &str = SPINNER_FRAMES[frame % SPINNER_FRAMES.len()];
+
+                print!(" {}", paint(glyph, Tone::Info));
+
+                let _ = queue!(stdout(), MoveLeft(2));
+                let _ = stdout().flush();
+
+                frame += 1;
+
+                // Woken at once on stop, so the first output is not held back
+                // until the next frame.
+                tokio::select! {
+                    () = stopped.notified() => break,
+                    () = tokio::time::sleep(Duration::from_millis(100)) => {}
+                }
+            }
+
+            let _ = execute!(stdout(), Clear(ClearType::UntilNewLine));
+        });
+
+        Spinner { done, handle }
+    }
+
+    async fn stop(self) {
+        self.done.notify_one();
+
+        let _ = self.handle.await;
+    }
+}
+
+struct Session {
+    last_output: Vec<String>,
+    transcript: Option<BufWriter<File>>,
+    started: Instant,
+    commands: usize,
+    received: usize,
+    /// How long each completed command took, in the order they ran.
+    timings: Vec<Duration>,
+}
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+// The session a SIGTERM or SIGINT disconnects before exiting, kept current
+// as the shell connects, reconnects and switches servers.
+static ACTIVE: Mutex<Option<LilDbConnection>> = Mutex::new(None);
+
+const KEYWORDS: [&str; 1] = ["exit"];
+
+const META_COMMANDS: [(&str, &str); 15] = [
+    (".help", "List the available meta-commands"),
+    (".clear", "Clear the terminal screen"),
+    (".history", "Print the command history"),
+    (".clearhistory", "Forget the command history"),
+    (".save", "Write the last result to a file: .save [path]"),
+    (".connect", "Switch to another server: .connect <address>"),
+    (".ping", "Show the round trip time to the server"),
+    (".describe", "Describe a table: .describe <name>"),
+    (".source", "Run a script: .source[!] <path>"),
+    (".repeat", "Run the last command again (also !!, !<n>)"),
+    (".stats", "Show commands run, uptime and bytes received"),
+    (".timings", "Summarize command latency: .timings [reset]"),
+    (".status", "Show the server, TLS and keepalive settings"),
+    (".alias", "List the aliases from the config file"),
+    (".exit", "Disconnect and quit, same as exit"),
+];
+
+const DEFAULT_PROMPT: &str = ">> ";
+
+// First words that make --confirm-destructive ask before sending.
+const DEFAULT_DESTRUCTIVE_KEYWORDS: [&str; 3] = ["DROP", "DELETE", "TRUNCATE"];
+
+// First words --read-only refuses to send.
+const DEFAULT_WRITE_KEYWORDS: [&str; 8] = [
+    "INSERT", "UPDATE", "DELETE", "DROP", "TRUNCATE", "CREATE", "ALTER", "RENAME",
+];
+
+const READ_ONLY_BLOCKED: &str = "read-only mode: command blocked";
+
+// Server command sent by .describe, with {} standing for the name.
+const DEFAULT_DESCRIBE_COMMAND: &str = "DESCRIBE {}";
+
+// Drawn in front of each line after the first in a multi-line command.
+const CONTINUATION_PROMPT: &str = ".. ";
+
+const SPINNER_FRAMES: [&str; 4] = ["|", "/", "-", "\\"];
+
+// Result output is buffered and flushed once the stream has been quiet for
+// FLUSH_AFTER, so large results cost a few writes instead of one per chunk.
+const OUTPUT_BUFFER: usize = 64 * 1024;
+const FLUSH_AFTER: Duration = Duration::from_millis(20);
+
+// Exit codes, so scripts can tell why the shell stopped; listed for users under
+// "Exit status" in USAGE.
+const EXIT_USAGE: i32 = 1;
+const EXIT_CONNECT: i32 = 2;
+const EXIT_REFUSED: i32 = 3;
+const EXIT_COMMAND: i32 = 4;
+// Shells report a process killed by signal N as 128 + N.
+const EXIT_SIGINT: i32 = 130;
+const EXIT_SIGTERM: i32 = 143;
+
+// How long a signal waits for the disconnect before exiting anyway.
+const SIGNAL_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn paint(text: &str, tone: Tone) -> String {
+    if !COLOR.load(Ordering::Relaxed) {
+        return text.to_string();
+    }
+
+    match tone {
+        Tone::Output => text.green().to_string(),
+        Tone::Error => text.red().to_string(),
+        Tone::Info => text.cyan().to_string(),
+        Tone::Prompt => text.blue().bold().to_string(),
+    }
+}
+
+// Cursor movement and clearing go through crossterm commands instead of raw
+// escape sequences, so consoles that crossterm drives through the Windows
+// console API redraw the same way ANSI terminals do.
+fn render_line(
+    prompt: &str,
+    input: &str,
+    cursor: usize,
+    row: &mut usize,
+) -> Result<(), Box<dyn Error>> {
+    if *row > 0 {
+        queue!(stdout(), MoveUp(*row as u16))?;
+    }
+
+    let continuation: String = format!("\n\r{}", paint(CONTINUATION_PROMPT, Tone::Prompt));
+
+    queue!(stdout(), MoveToColumn(0), Clear(ClearType::FromCursorDown))?;
+
+    print!(
+        "{}{}",
+        paint(prompt, Tone::Prompt),
+        input.replace('\n', &continuation)
+    );
+
+    let columns: usize = terminal_columns(terminal::size());
+
+    let layout: Layout = layout(prompt, input, cursor, columns);
+
+    // Force the pending wrap so the cursor can sit after the last character.
+    if layout.pending_wrap {
+        print!(" ");
+
+        queue!(stdout(), MoveToColumn(0), Clear(ClearType::UntilNewLine))?;
+    }
+
+    if layout.end_row > layout.cursor_row {
+        queue!(
+            stdout(),
+            MoveUp((layout.end_row - layout.cursor_row) as u16)
+        )?;
+    }
+
+    queue!(stdout(), MoveToColumn(layout.column as u16))?;
+
+    *row = layout.cursor_row;
+
+    stdout().flush()?;
+
+    Ok(())
+}
+
+// Where a rendered line ends and where its cursor sits, in screen rows below
+// the prompt and the column within the cursor's row.
+#[derive(Debug, PartialEq)]
+struct Layout {
+    end_row: usize,
+    cursor_row: usize,
+    column: usize,
+    // The last line exactly fills the width, leaving the terminal waiting to
+    // wrap.
+    pending_wrap: bool,
+}
+
+// Rows are counted on screen, so lines that wrap at the terminal width take
+// as many rows as they fill, and wide characters count as two columns.
+fn layout(prompt: &str, input: &str, cursor: usize, columns: usize) -> Layout {
+    let widths: Vec<usize> = input
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 {
+                prompt.width() + line.width()
+            } else {
+                CONTINUATION_PROMPT.width() + line.width()
+            }
+        })
+        .collect();
+
+    let span = |width: usize| width.div_ceil(columns).max(1);
+
+    let last: usize = widths.len() - 1;
+
+    let end_row: usize = widths[..last]
+        .iter()
+        .map(|&width| span(width))
+        .sum::<usize>()
+        + widths[last] / columns;
+
+    let before: Vec<&str> = input[..cursor].split('\n').collect();
+    let line: usize = before.len() - 1;
+
+    let mut column: usize = before[line].width();
+
+    if line == 0 {
+        column += prompt.width();
+    } else {
+        column += CONTINUATION_PROMPT.width();
+    }
+
+    let cursor_row: usize = widths[..line]
+        .iter()
+        .map(|&width| span(width))
+        .sum::<usize>()
+        + column / columns;
+
+    Layout {
+        end_row,
+        cursor_row,
+        column: column % columns,
+        pending_wrap: widths[last] > 0 && widths[last].is_multiple_of(columns),
+    }
+}
+
+fn clear_input(
+    prompt: &Prompt,
+    input: &str,
+    cursor: usize,
+    row: &mut usize,
+) -> Result<(), Box<dyn Error>> {
+    render_line(&prompt.render(), input, cursor, row)
+}
+
+fn clear_search(query: &str, matched: Option<&str>, row: &mut usize) -> Result<(), Box<dyn Error>> {
+    let prompt: String = format!("(reverse-i-search)`{}': ", query);
+    let text: &str = matched.unwrap_or("");
+    let cursor: usize = text.find(query).unwrap_or(0);
+
+    render_line(&prompt, text, cursor, row)
+}
+
+fn find_match(history: &[String], query: &str, before: usize) -> Option<usize> {
+    if query.is_empty() {
+        return None;
+    }
+
+    history[..before]
+        .iter()
+        .rposition(|command| command.contains(query))
+}
+
+fn completions(history: &[String], prefix: &str) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+
+    let entries = history
+        .iter()
+        .rev()
+        .map(String::as_str)
+        .chain(KEYWORDS.iter().copied())
+        .chain(META_COMMANDS.iter().map(|(name, _)| *name));
+
+    for entry in entries {
+        if entry.len() > prefix.len()
+            && entry.starts_with(prefix)
+            && !candidates.iter().any(|candidate| candidate == entry)
+        {
+            candidates.push(entry.to_string());
+        }
+    }
+
+    candidates
+}
+
+fn common_prefix(candidates: &[String]) -> &str {
+    let first: &str = &candidates[0];
+    let mut end: usize = first.len();
+
+    for candidate in &candidates[1..] {
+        end = first
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(end.min(candidate.len()), |((i, _), _)| i.min(end));
+    }
+
+    &first[..end]
+}
+
+fn prev_boundary(input: &str, cursor: usize) -> usize {
+    input[..cursor]
+        .grapheme_indices(true)
+        .next_back()
+        .map_or(0, |(i, _)| i)
+}
+
+fn next_boundary(input: &str, cursor: usize) -> usize {
+    input[cursor..]
+        .graphemes(true)
+        .next()
+        .map_or(cursor, |grapheme| cursor + grapheme.len())
+}
+
+fn word_start(input: &str, cursor: usize) -> usize {
+    let before: &str = input[..cursor].trim_end();
+
+    before.rfind(char::is_whitespace).map_or(0, |i| {
+        i + before[i..].chars().next().map_or(1, char::len_utf8)
+    })
+}
+
+fn line_start(input: &str, cursor: usize) -> usize {
+    input[..cursor].rfind('\n').map_or(0, |i| i + 1)
+}
+
+fn line_end(input: &str, cursor: usize) -> usize {
+    input[cursor..]
+        .find('\n')
+        .map_or(input.len(), |i| cursor + i)
+}
+
+// Some terminals, such as a serial console or a pty nobody sized, report zero
+// columns; that is treated like not knowing and falls back to 80.
+fn terminal_columns(size: io::Result<(u16, u16)>) -> usize {
+    match size {
+        Ok((columns, _)) if columns > 0 => columns as usize,
+        _ => 80,
+    }
+}
+
+fn scroll_output(output: &[String], code: KeyCode) -> Result<(), Box<dyn Error>> {
+    let lines: Vec<&str> = output.iter().flat_map(|chunk| chunk.lines()).collect();
+
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    let (_, height): (u16, u16) = terminal::size()?;
+    let page: usize = (height as usize).saturating_sub(1).max(1);
+    let bottom: usize = lines.len().saturating_sub(page);
+
+    let mut top: usize = match code {
+        KeyCode::PageUp => bottom.saturating_sub(page),
+        _ => bottom,
+    };
+
+    execute!(stdout(), EnterAlternateScreen)?;
+
+    loop {
+        queue!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+
+        for line in lines.iter().skip(top).take(page) {
+            print!("{}\n\r", line);
+        }
+
+        let status: String = format!(
+            "-- lines {}-{} of {} (PgUp/PgDn: scroll, any other key: back) --",
+            top + 1,
+            (top + page).min(lines.len()),
+            lines.len()
+        );
+
+        print!("{}", paint(&status, Tone::Info));
+
+        stdout().flush()?;
+
+        let code: KeyCode = loop {
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = read()?
+            {
+                break code;
+            }
+        };
+
+        match code {
+            KeyCode::PageUp => top = top.saturating_sub(page),
+            KeyCode::PageDown => top = (top + page).min(bottom),
+            _ => break,
+        }
+    }
+
+    execute!(stdout(), LeaveAlternateScreen)?;
+
+    Ok(())
+}
+
+// Where Up and Down have got to in history. `draft` keeps the line typed
+// before the first Up, so Down past the newest entry brings it back.
+struct Recall {
+    index: usize,
+    draft: String,
+}
+
+impl Recall {
+    fn new(history: &[String]) -> Recall {
+        Recall {
+            index: history.len(),
+            draft: String::new(),
+        }
+    }
+
+    // Back to the newest end of history, as for a freshly typed line.
+    fn reset(&mut self, history: &[String]) {
+        self.index = history.len();
+        self.draft.clear();
+    }
+
+    fn up(&mut self, history: &[String], input: &str) -> Option<String> {
+        if self.index == 0 {
+            return None;
+        }
+
+        if self.index == history.len() {
+            self.draft = input.to_string();
+        }
+
+        self.index -= 1;
+
+        Some(history[self.index].clone())
+    }
+
+    fn down(&mut self, history: &[String]) -> Option<String> {
+        if self.index >= history.len() {
+            return None;
+        }
+
+        self.index += 1;
+
+        if self.index == history.len() {
+            Some(self.draft.clone())
+        } else {
+            Some(history[self.index].clone())
+        }
+    }
+}
+
+// Returns whether the line was interrupted with Ctrl+C, or None when no key
+// was pressed for `idle`.
+fn read_input(
+    input: &mut String,
+    history: &[String],
+    scrollback: &[String],
+    prompt: &Prompt,
+    idle: Option<Duration>,
+) -> Result<Option<bool>, Box<dyn Error>> {
+    let mut cursor: usize = input.len();
+    let mut row: usize = 0;
+
+    let mut recall: Recall = Recall::new(history);
+
+    let mut search: Option<String> = None;
+    let mut search_match: Option<usize> = None;
+
+    clear_input(prompt, input, cursor, &mut row)?;
+
+    loop {
+        if let Some(idle) = idle {
+            if !poll(idle)? {
+                return Ok(None);
+            }
+        }
+
+        let (code, kind, modifiers): (KeyCode, KeyEventKind, KeyModifiers) = match read()? {
+            Event::Key(KeyEvent {
+                code,
+                kind,
+                modifiers,
+                state: _,
+            }) => (code, kind, modifiers),
+            Event::Resize(_, _) => {
+                match &search {
+                    Some(query) => {
+                        let matched: Option<&str> = search_match.map(|i| history[i].as_str());
+
+                        clear_search(query, matched, &mut row)?;
+                    }
+                    None => clear_input(prompt, input, cursor, &mut row)?,
+                }
+
+                continue;
+            }
+            Event::Paste(text) if search.is_none() => {
+                let text: String = text.replace("\r\n", "\n").replace('\r', "\n");
+
+                input.insert_str(cursor, &text);
+                cursor += text.len();
+
+                clear_input(prompt, input, cursor, &mut row)?;
+
+                continue;
+            }
+            _ => continue,
+        };
+
+        if kind != KeyEventKind::Press {
+            continue;
+        }
+
+        if let Some(query) = search.as_mut() {
+            match (code, modifiers) {
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                    search = None;
+
+                    input.clear();
+                    cursor = 0;
+
+                    clear_input(prompt, input, cursor, &mut row)?;
+
+                    continue;
+                }
+                (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                    let before: usize = search_match.unwrap_or(history.len());
+
+                    if let Some(i) = find_match(history, query, before) {
+                        search_match = Some(i);
+                    }
+                }
+                (KeyCode::Char(c), modifiers) if !modifiers.contains(KeyModifiers::CONTROL) => {
+                    query.push(c);
+
+                    let before: usize = search_match.map_or(history.len(), |i| i + 1);
+
+                    search_match = find_match(history, query, before);
+                }
+                (KeyCode::Backspace, _) => {
+                    query.pop();
+
+                    search_match = find_match(history, query, history.len());
+                }
+                (KeyCode::Enter, _) => {
+                    if let Some(i) = search_match {
+                        *input = history[i].clone();
+                        cursor = input.len();
+                    }
+
+                    search = None;
+
+                    clear_input(prompt, input, cursor, &mut row)?;
+
+                    continue;
+                }
+                (KeyCode::Esc, _) => {
+                    search = None;
+
+                    clear_input(prompt, input, cursor, &mut row)?;
+
+                    continue;
+                }
+                _ => {}
+            }
+
+            let matched: Option<&str> = search_match.map(|i| history[i].as_str());
+
+            clear_search(query, matched, &mut row)?;
+
+            continue;
+        }
+
+        match (code, modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                search = Some(String::new());
+                search_match = None;
+
+                clear_search("", None, &mut row)?;
+            }
+            (KeyCode::Enter, KeyModifiers::ALT) => {
+                input.insert(cursor, '\n');
+                cursor += 1;
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Enter, _) if !input.is_empty() => {
+                cursor = input.len();
+
+                clear_input(prompt, input, cursor, &mut row)?;
+
+                break;
+            }
+            (KeyCode::Enter, _) => {
+                // Start over on a fresh line, so an emptied recalled entry visibly resets.
+                print!("\n\r");
+
+                row = 0;
+                recall.reset(history);
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Backspace, _) if cursor > 0 => {
+                let start: usize = prev_boundary(input, cursor);
+
+                input.replace_range(start..cursor, "");
+                cursor = start;
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Delete, _) if cursor < input.len() => {
+                let end: usize = next_boundary(input, cursor);
+
+                input.replace_range(cursor..end, "");
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Left, _) if cursor > 0 => {
+                cursor = prev_boundary(input, cursor);
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Right, _) if cursor < input.len() => {
+                cursor = next_boundary(input, cursor);
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Up | KeyCode::Down, _) => {
+                let entry: Option<String> = match code {
+                    KeyCode::Up => recall.up(history, input),
+                    _ => recall.down(history),
+                };
+
+                if let Some(entry) = entry {
+                    *input = entry;
+                    cursor = input.len();
+
+                    clear_input(prompt, input, cursor, &mut row)?;
+                }
+            }
+            (KeyCode::PageUp | KeyCode::PageDown, _) if !scrollback.is_empty() => {
+                scroll_output(scrollback, code)?;
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Home, _) | (KeyCode::Char('a'), KeyModifiers::CONTROL) => {
+                cursor = line_start(input, cursor);
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::End, _) | (KeyCode::Char('e'), KeyModifiers::CONTROL) => {
+                cursor = line_end(input, cursor);
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Tab, _) if cursor > 0 => {
+                let candidates: Vec<String> = completions(history, &input[..cursor]);
+
+                if candidates.is_empty() {
+                    continue;
+                }
+
+                if candidates.len() > 1 {
+                    clear_input(prompt, input, input.len(), &mut row)?;
+
+                    print!("\n\r{}\n\r", candidates.join("  "));
+
+                    row = 0;
+                }
+
+                let completion: String = common_prefix(&candidates)[cursor..].to_string();
+
+                input.insert_str(cursor, &completion);
+                cursor += completion.len();
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Char('w'), KeyModifiers::CONTROL) if cursor > 0 => {
+                let start: usize = word_start(input, cursor);
+
+                input.replace_range(start..cursor, "");
+                cursor = start;
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Char('u'), KeyModifiers::CONTROL) if cursor > 0 => {
+                let start: usize = line_start(input, cursor);
+
+                input.replace_range(start..cursor, "");
+                cursor = start;
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Char('w' | 'u'), KeyModifiers::CONTROL) => {}
+            (KeyCode::Char('l'), KeyModifiers::CONTROL) => {
+                queue!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+
+                row = 0;
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            // Ctrl+C abandons a half-typed line and only exits from an empty one.
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) if !input.is_empty() => {
+                clear_input(prompt, input, input.len(), &mut row)?;
+
+                print!("^C\n\r");
+
+                input.clear();
+                cursor = 0;
+                row = 0;
+                recall.reset(history);
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(Some(true)),
+            (KeyCode::Char(c), _) => {
+                input.insert(cursor, c);
+                cursor += c.len_utf8();
+
+                clear_input(prompt, input, cursor, &mut row)?;
+            }
+            _ => {} // _ => println!("{:?} {:?}", code, modifiers),
+        }
+    }
+
+    Ok(Some(false))
+}
+
+// Restores the terminal when dropped, so early returns leave it usable.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = restore_terminal();
+    }
+}
+
+fn setup_terminal() -> io::Result<TerminalGuard> {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+
+        default_hook(info);
+    }));
+
+    enable_raw_mode()?;
+
+    execute!(stdout(), EnableBracketedPaste)?;
+
+    Ok(TerminalGuard)
+}
+
+fn restore_terminal() -> io::Result<()> {
+    if !is_raw_mode_enabled()? {
+        return Ok(());
+    }
+
+    execute!(stdout(), DisableBracketedPaste)?;
+
+    disable_raw_mode()
+}
+
+// Log output, kept off stdout so piped results stay clean. In raw mode a bare newline moves down without
+// returning to the first column, so lines are ended with \r\n while it is on.
+struct RawLines(Stderr);
+
+impl Write for RawLines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !is_raw_mode_enabled().unwrap_or(false) {
+            return self.0.write(buf);
+        }
+
+        self.0.write_all(&raw_newlines(buf))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn raw_newlines(buf: &[u8]) -> Vec<u8> {
+    let mut converted: Vec<u8> = Vec::with_capacity(buf.len());
+
+    for (i, &byte) in buf.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || buf[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+
+        converted.push(byte);
+    }
+
+    converted
+}
+
+// process::exit skips destructors, so exits go through here to restore the
+// terminal first.
+fn exit(code: i32) -> ! {
+    let _ = restore_terminal();
+
+    process::exit(code)
+}
+
+fn set_active(client: &LilDbConnection) {
+    if let Ok(mut active) = ACTIVE.lock() {
+        *active = Some(client.clone());
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> i32 {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => Some(terminate),
+        Err(e) => {
+            warn!("Could not listen for SIGTERM: {}", e);
+
+            None
+        }
+    };
+
+    tokio::select! {
+        Some(_) = async { terminate.as_mut()?.recv().await } => EXIT_SIGTERM,
+        Ok(()) = tokio::signal::ctrl_c() => EXIT_SIGINT,
+        else => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> i32 {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => EXIT_SIGINT,
+        Err(_) => std::future::pending().await,
+    }
+}
+
+// On SIGTERM or SIGINT, tells the server the session is over (best effort)
+// and exits, restoring the terminal on the way out.
+fn watch_signals() {
+    tokio::spawn(async {
+        let code: i32 = wait_for_signal().await;
+
+        let active: Option<LilDbConnection> = ACTIVE.lock().ok().and_then(|active| active.clone());
+
+        if let Some(mut client) = active {
+            match tokio::time::timeout(SIGNAL_DISCONNECT_TIMEOUT, client.disconnect()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Failed to disconnect: {}", e),
+                Err(_) => warn!("Timed out disconnecting"),
+            }
+        }
+
+        exit(code);
+    });
+}
+
+fn home_dir() -> PathBuf {
+    let home: String = env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .unwrap_or_default();
+
+    PathBuf::from(home)
+}
+
+fn default_history_file() -> PathBuf {
+    home_dir().join(".lildbsh_history")
+}
+
+// Why check_args stopped without a Config: output the user asked for instead
+// of a session, or a mistake on the command line.
+#[derive(Debug, PartialEq)]
+enum UsageError {
+    Help,
+    Version,
+    Completions(String),
+    Invalid(String),
+}
+
+impl UsageError {
+    fn exit(self) -> ! {
+        match self {
+            UsageError::Help => print!("{}", USAGE),
+            UsageError::Version => println!("LilDBsh {}", env!("CARGO_PKG_VERSION")),
+            UsageError::Completions(script) => print!("{}", script),
+            UsageError::Invalid(message) => {
+                eprintln!("{}", message);
+
+                exit(EXIT_USAGE);
+            }
+        }
+
+        exit(0)
+    }
+}
+
+// The environment variables check_args takes defaults from, read once in main.
+#[derive(Default)]
+struct Environment {
+    address: Option<String>,
+    token: Option<String>,
+    prompt: Option<String>,
+}
+
+impl Environment {
+    fn from_process() -> Environment {
+        let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+        Environment {
+            address: non_empty("LILDBSH_ADDRESS"),
+            token: non_empty("LILDBSH_TOKEN"),
+            prompt: env::var("LILDBSH_PROMPT").ok(),
+        }
+    }
+}
+
+fn read_config_file(args: &[String]) -> Result<ConfigFile, UsageError> {
+    let explicit: Option<String> = match args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .position(|arg| arg == "--config")
+    {
+        Some(mut i) => Some(next_value(args, &mut i)?),
+        None => None,
+    };
+
+    let path: PathBuf = match &explicit {
+        Some(path) => PathBuf::from(path),
+        None => home_dir().join(".config/lildbsh/config.toml"),
+    };
+
+    let contents: String = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound && explicit.is_none() => {
+            return Ok(ConfigFile::default())
+        }
+        Err(e) => {
+            return Err(UsageError::Invalid(format!(
+                "Could not read config file {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+
+    match toml::from_str(&contents) {
+        Ok(file) => Ok(file),
+        Err(e) => Err(UsageError::Invalid(format!(
+            "Invalid config file {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+// Help, the version and completion scripts do not depend on the config file,
+// so a broken one only stops a real session from starting.
+fn load_config(args: &[String], environment: &Environment) -> Result<Config, UsageError> {
+    match read_config_file(args) {
+        Ok(file) => check_args(args, environment, file),
+        Err(broken) => match check_args(args, environment, ConfigFile::default()) {
+            Ok(_) | Err(UsageError::Invalid(_)) => Err(broken),
+            Err(output) => Err(output),
+        },
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FlagArg {
+    Switch,
+    Path,
+    Value,
+}
+
+// Printed by -h/--help. Keep it in step with FLAGS.
+const USAGE: &str = "\
+Usage: LilDBsh [options] [address] [-- command...]
+
+Connection:
+  -a, --address <list>         Server address(es), comma-separated; tried in order
+      --tls                    Connect over TLS
+      --insecure               Accept any TLS certificate (testing only)
+      --ca-cert <path>         CA certificate to trust
+      --client-cert <path>     Client certificate for mutual TLS
+      --client-key <path>      Key for --client-cert
+      --token <token>          Auth token sent with every request
+      --token-file <path>      Read the auth token from a file
+      --retries <n>            Connect attempts after the first (default 3)
+      --retry-delay <ms>       Delay before the first retry, doubled each time
+      --retry-jitter           Spread retry delays randomly (default)
+      --no-retry-jitter        Retry at exact delays
+      --connect-timeout <ms>   Timeout for each connect attempt
+      --keepalive-interval <s> Seconds between keepalive pings
+      --keepalive-timeout <s>  Seconds to wait for a keepalive ack
+      --no-keepalive           Send no keepalive pings
+      --bind <ip>              Local address to connect from
+      --compression <kind>     none or gzip
+      --encoding <charset>     Charset of raw server output (default utf-8)
+      --max-command-bytes <n>  Longest command sent (default 4 MiB)
+      --public-ip <ip>         IP sent to the server instead of looking it up
+      --no-public-ip           Never look the public IP up online
+      --no-reconnect           Exit instead of reconnecting on a lost connection
+
+Commands:
+  -c, --command <command>      Run one command and exit
+      --file <path>            Run the commands in a file and exit
+      --file-format <kind>     plain or jsonl
+      --continue-on-error      Keep running --file after a failed command
+      --init <path>            Script run before the prompt appears
+      --strict-init            Exit if the init script fails
+      --dry-run                Print commands instead of sending them
+      --timeout <ms>           Give up on a command after this long
+      --read-only              Block commands that start with a write keyword
+      --confirm-destructive    Ask before sending destructive commands
+
+Output:
+      --format <kind>          text, json or csv
+  -t, --timing                 Show how long each command took
+      --line-numbers           Number output lines
+      --table                  Lay tabular output out as a table
+      --table-style <style>    ascii, markdown or borderless
+      --output <path>          Also append results to a file
+      --max-output-lines <n>   Truncate longer results
+      --pager <mode>           off, auto or always
+      --no-stream              Show each reply only once it is complete
+      --no-spinner             Hide the progress spinner
+      --no-color               Disable colors
+  -q, --quiet                  Only log warnings and errors
+      --verbose                Log debug details
+      --log-file <path>        Also write logs to a file
+
+Shell:
+      --config <path>          Config file (default ~/.config/lildbsh/config.toml)
+      --prompt <text>          Prompt text
+      --history-file <path>    Where history is kept
+      --history-size <n>       Entries kept in history
+      --history-dedup-all      Drop every earlier copy of a repeated command
+      --no-history             Record no history this session
+      --idle-timeout <s>       Disconnect after this long without input
+      --no-trim                Send commands without trimming whitespace
+      --no-raw                 Read whole lines instead of using raw mode
+
+  -h, --help                   Print this help
+  -V, --version                Print the version
+
+Exit status:
+  0    success
+  1    invalid command-line arguments
+  2    could not reach the server
+  3    the server refused the connection
+  4    a command or the command stream failed
+  130  interrupted by SIGINT or Ctrl+C
+  143  stopped by SIGTERM
+";
+
+// Every flag check_args accepts, used to generate shell completions.
+const FLAGS: [(&str, FlagArg); 64] = [
+    ("-a", FlagArg::Value),
+    ("--address", FlagArg::Value),
+    ("--config", FlagArg::Path),
+    ("-h", FlagArg::Switch),
+    ("--help", FlagArg::Switch),
+    ("-V", FlagArg::Switch),
+    ("--version", FlagArg::Switch),
+    ("--history-file", FlagArg::Path),
+    ("--history-dedup-all", FlagArg::Switch),
+    ("--history-size", FlagArg::Value),
+    ("--tls", FlagArg::Switch),
+    ("--insecure", FlagArg::Switch),
+    ("--ca-cert", FlagArg::Path),
+    ("--client-cert", FlagArg::Path),
+    ("--client-key", FlagArg::Path),
+    ("--retries", FlagArg::Value),
+    ("--max-command-bytes", FlagArg::Value),
+    ("--retry-delay", FlagArg::Value),
+    ("--retry-jitter", FlagArg::Switch),
+    ("--no-retry-jitter", FlagArg::Switch),
+    ("--keepalive-interval", FlagArg::Value),
+    ("--keepalive-timeout", FlagArg::Value),
+    ("--no-keepalive", FlagArg::Switch),
+    ("--bind", FlagArg::Value),
+    ("--connect-timeout", FlagArg::Value),
+    ("--file", FlagArg::Path),
+    ("--continue-on-error", FlagArg::Switch),
+    ("--file-format", FlagArg::Value),
+    ("--dry-run", FlagArg::Switch),
+    ("--init", FlagArg::Path),
+    ("--strict-init", FlagArg::Switch),
+    ("-c", FlagArg::Value),
+    ("--command", FlagArg::Value),
+    ("--timeout", FlagArg::Value),
+    ("--no-trim", FlagArg::Switch),
+    ("--idle-timeout", FlagArg::Value),
+    ("--no-reconnect", FlagArg::Switch),
+    ("--no-color", FlagArg::Switch),
+    ("--prompt", FlagArg::Value),
+    ("--format", FlagArg::Value),
+    ("-t", FlagArg::Switch),
+    ("--timing", FlagArg::Switch),
+    ("--line-numbers", FlagArg::Switch),
+    ("--table", FlagArg::Switch),
+    ("--table-style", FlagArg::Value),
+    ("--output", FlagArg::Path),
+    ("--max-output-lines", FlagArg::Value),
+    ("--no-spinner", FlagArg::Switch),
+    ("--pager", FlagArg::Value),
+    ("--no-raw", FlagArg::Switch),
+    ("--no-stream", FlagArg::Switch),
+    ("--confirm-destructive", FlagArg::Switch),
+    ("--no-history", FlagArg::Switch),
+    ("--read-only", FlagArg::Switch),
+    ("-q", FlagArg::Switch),
+    ("--quiet", FlagArg::Switch),
+    ("--verbose", FlagArg::Switch),
+    ("--log-file", FlagArg::Path),
+    ("--public-ip", FlagArg::Value),
+    ("--no-public-ip", FlagArg::Switch),
+    ("--compression", FlagArg::Value),
+    ("--encoding", FlagArg::Value),
+    ("--token", FlagArg::Value),
+    ("--token-file", FlagArg::Path),
+];
+
+fn completion_script(shell: &str) -> Option<String> {
+    let names = |arg: FlagArg| -> Vec<&str> {
+        FLAGS
+            .iter()
+            .filter(|(_, kind)| *kind == arg)
+            .map(|(name, _)| *name)
+            .collect()
+    };
+
+    let all: Vec<&str> = FLAGS.iter().map(|(name, _)| *name).collect();
+
+    let script: String = match shell {
+        "bash" => format!(
+            "_lildbsh() {{\n    \
+                local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\n    \
+                case \"$prev\" in\n        \
+                    {}) COMPREPLY=($(compgen -f -- \"$cur\")); return ;;\n        \
+                    {}) return ;;\n    \
+                esac\n\n    \
+                COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n\
+            }}\n\n\
+            complete -F _lildbsh lildbsh LilDBsh\n",
+            names(FlagArg::Path).join("|"),
+            names(FlagArg::Value).join("|"),
+            all.join(" ")
+        ),
+        "zsh" => {
+            let specs: Vec<String> = FLAGS
+                .iter()
+                .map(|(name, arg)| match arg {
+                    FlagArg::Switch => format!("    '{}'", name),
+                    FlagArg::Path => format!("    '{}:path:_files'", name),
+                    FlagArg::Value => format!("    '{}:value: '", name),
+                })
+                .collect();
+
+            format!(
+                "#compdef lildbsh LilDBsh\n\n_arguments \\\n{}\n",
+                specs.join(" \\\n")
+            )
+        }
+        "fish" => FLAGS
+            .iter()
+            .flat_map(|(name, arg)| {
+                let option: String = match name.strip_prefix("--") {
+                    Some(long) => format!("-l {}", long),
+                    None => format!("-s {}", &name[1..]),
+                };
+
+                let value: &str = match arg {
+                    FlagArg::Switch => "",
+                    FlagArg::Path => " -r -F",
+                    FlagArg::Value => " -r -f",
+                };
+
+                ["lildbsh", "LilDBsh"]
+                    .map(|command| format!("complete -c {} {}{}\n", command, option, value))
+            })
+            .collect(),
+        _ => return None,
+    };
+
+    Some(script)
+}
+
+fn next_value(args: &[String], i: &mut usize) -> Result<String, UsageError> {
+    let flag: &str = &args[*i];
+
+    *i += 1;
+
+    match args.get(*i) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(UsageError::Invalid(format!("Missing value for {}", flag))),
+    }
+}
+
+fn parse_value<T: FromStr>(args: &[String], i: &mut usize) -> Result<T, UsageError> {
+    let flag: String = args[*i].to_string();
+    let value: String = next_value(args, i)?;
+
+    match value.parse() {
+        Ok(parsed) => Ok(parsed),
+        Err(_) => Err(UsageError::Invalid(format!(
+            "Invalid value for {}: {}",
+            flag, value
+        ))),
+    }
+}
+
+fn check_args(
+    args: &[String],
+    environment: &Environment,
+    file: ConfigFile,
+) -> Result<Config, UsageError> {
+    let defaults: ConnectOptions = ConnectOptions::default();
+
+    let mut config: Config = Config {
+        addresses: environment
+            .address
+            .clone()
+            .or(file.address)
+            .map_or_else(Vec::new, |list| split_addresses(&list)),
+        history_file: file.history_file.unwrap_or_else(default_history_file),
+        history_dedup_all: false,
+        history_size: file.history_size.unwrap_or(1000),
+        connection: ConnectOptions {
+            tls: file.tls.unwrap_or(defaults.tls),
+            ca_cert: file.ca_cert,
+            client_cert: file.client_cert,
+            client_key: file.client_key,
+            retries: file.retries.unwrap_or(defaults.retries),
+            retry_delay: file.retry_delay.unwrap_or(defaults.retry_delay),
+            connect_timeout: file.connect_timeout.unwrap_or(defaults.connect_timeout),
+            max_command_bytes: file.max_command_bytes.unwrap_or(defaults.max_command_bytes),
+            token: environment.token.clone(),
+            ..defaults
+        },
+        file: None,
+        continue_on_error: false,
+        command: None,
+        no_reconnect: false,
+        no_color: false,
+        prompt: environment
+            .prompt
+            .clone()
+            .or(file.prompt)
+            .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+        format: Format::Text,
+        timing: false,
+        pager: Pager::Off,
+        log_level: Level::INFO,
+        public_ip: file.public_ip,
+        aliases: file.alias.unwrap_or_default(),
+        describe_command: file
+            .describe_command
+            .unwrap_or_else(|| DEFAULT_DESCRIBE_COMMAND.to_string()),
+        destructive_keywords: file.destructive_keywords.unwrap_or_else(|| {
+            DEFAULT_DESTRUCTIVE_KEYWORDS
+                .iter()
+                .map(|keyword| keyword.to_string())
+                .collect()
+        }),
+        write_keywords: file.write_keywords.unwrap_or_else(|| {
+            DEFAULT_WRITE_KEYWORDS
+                .iter()
+                .map(|keyword| keyword.to_string())
+                .collect()
+        }),
+        line_numbers: false,
+        init: None,
+        strict_init: false,
+        quiet: false,
+        no_spinner: false,
+        log_file: None,
+        max_output_lines: None,
+        output: None,
+        no_public_ip: false,
+        table: false,
+        table_style: TableStyle::Ascii,
+        dry_run: false,
+        file_format: FileFormat::Plain,
+        idle_timeout: None,
+        no_trim: false,
+        timeout: None,
+        no_raw: false,
+        no_stream: false,
+        confirm_destructive: false,
+        no_history: false,
+        read_only: false,
+    };
+
+    let mut i: usize = 0;
+    let mut addresses: Vec<String> = Vec::new();
+    let mut positional: Option<String> = None;
+
+    while i < args.len() {
+        match args[i].as_str() {
+            "--" => {
+                if args.len() == i + 1 {
+                    return Err(UsageError::Invalid("Missing command after --".to_string()));
+                }
+
+                if config.command.is_some() {
+                    return Err(UsageError::Invalid(
+                        "Give the command either with --command or after --, not both".to_string(),
+                    ));
+                }
+
+                config.command = Some(args[i + 1..].join(" "));
+
+                break;
+            }
+            "-a" | "--address" => {
+                let list: Vec<String> = split_addresses(&next_value(args, &mut i)?);
+
+                if list.is_empty() {
+                    return Err(UsageError::Invalid("--address cannot be empty".to_string()));
+                }
+
+                addresses.extend(list);
+            }
+            "--config" => {
+                next_value(args, &mut i)?;
+            }
+            "--generate-completions" => {
+                let shell: String = next_value(args, &mut i)?;
+
+                match completion_script(&shell) {
+                    Some(script) => return Err(UsageError::Completions(script)),
+                    None => {
+                        return Err(UsageError::Invalid(format!(
+                            "Unsupported shell {}, use bash, zsh or fish",
+                            shell
+                        )));
+                    }
+                }
+            }
+            "-h" | "--help" => return Err(UsageError::Help),
+            "-V" | "--version" => return Err(UsageError::Version),
+            "--history-file" => config.history_file = PathBuf::from(next_value(args, &mut i)?),
+            "--history-dedup-all" => config.history_dedup_all = true,
+            "--history-size" => config.history_size = parse_value(args, &mut i)?,
+            "--tls" => config.connection.tls = true,
+            "--insecure" => config.connection.insecure = true,
+            "--ca-cert" => {
+                config.connection.ca_cert = Some(PathBuf::from(next_value(args, &mut i)?))
+            }
+            "--client-cert" => {
+                config.connection.client_cert = Some(PathBuf::from(next_value(args, &mut i)?))
+            }
+            "--client-key" => {
+                config.connection.client_key = Some(PathBuf::from(next_value(args, &mut i)?))
+            }
+            "--retries" => config.connection.retries = parse_value(args, &mut i)?,
+            "--max-command-bytes" => {
+                config.connection.max_command_bytes = parse_value(args, &mut i)?
+            }
+            "--retry-delay" => config.connection.retry_delay = parse_value(args, &mut i)?,
+            "--retry-jitter" => config.connection.retry_jitter = true,
+            "--no-retry-jitter" => config.connection.retry_jitter = false,
+            "--keepalive-interval" => {
+                config.connection.keepalive_interval = Some(parse_value(args, &mut i)?)
+            }
+            "--keepalive-timeout" => {
+                config.connection.keepalive_timeout = parse_value(args, &mut i)?
+            }
+            "--no-keepalive" => config.connection.keepalive = false,
+            "--bind" => config.connection.bind = Some(parse_value(args, &mut i)?),
+            "--connect-timeout" => config.connection.connect_timeout = parse_value(args, &mut i)?,
+            "--file" => config.file = Some(PathBuf::from(next_value(args, &mut i)?)),
+            "--continue-on-error" => config.continue_on_error = true,
+            "--file-format" => config.file_format = parse_value(args, &mut i)?,
+            "--dry-run" => config.dry_run = true,
+            "--init" => config.init = Some(PathBuf::from(next_value(args, &mut i)?)),
+            "--strict-init" => config.strict_init = true,
+            "-c" | "--command" => config.command = Some(next_value(args, &mut i)?),
+            "--timeout" => config.timeout = Some(parse_value(args, &mut i)?),
+            "--no-trim" => config.no_trim = true,
+            "--idle-timeout" => config.idle_timeout = Some(parse_value(args, &mut i)?),
+            "--no-reconnect" => config.no_reconnect = true,
+            "--no-color" => config.no_color = true,
+            "--prompt" => config.prompt = next_value(args, &mut i)?,
+            "--format" => config.format = parse_value(args, &mut i)?,
+            "-t" | "--timing" => config.timing = true,
+            "--line-numbers" => config.line_numbers = true,
+            "--table" => config.table = true,
+            "--table-style" => {
+                config.table = true;
+                config.table_style = parse_value(args, &mut i)?;
+            }
+            "--output" => config.output = Some(PathBuf::from(next_value(args, &mut i)?)),
+            "--max-output-lines" => config.max_output_lines = Some(parse_value(args, &mut i)?),
+            "--no-spinner" => config.no_spinner = true,
+            "--pager" => config.pager = parse_value(args, &mut i)?,
+            "--no-raw" => config.no_raw = true,
+            "--no-stream" => config.no_stream = true,
+            "--confirm-destructive" => config.confirm_destructive = true,
+            "--no-history" => config.no_history = true,
+            "--read-only" => config.read_only = true,
+            "-q" | "--quiet" => {
+                config.log_level = Level::WARN;
+                config.quiet = true;
+            }
+            "--verbose" => config.log_level = Level::DEBUG,
+            "--log-file" => config.log_file = Some(PathBuf::from(next_value(args, &mut i)?)),
+            "--public-ip" => config.public_ip = Some(next_value(args, &mut i)?),
+            "--no-public-ip" => config.no_public_ip = true,
+            "--compression" => config.connection.compression = parse_value(args, &mut i)?,
+            "--encoding" => {
+                let value: String = next_value(args, &mut i)?;
+
+                match Encoding::for_label(value.as_bytes()) {
+                    Some(encoding) => config.connection.encoding = encoding,
+                    None => {
+                        return Err(UsageError::Invalid(format!("Unknown encoding: {}", value)));
+                    }
+                }
+            }
+            "--token" => config.connection.token = Some(next_value(args, &mut i)?),
+            "--token-file" => {
+                let path: String = next_value(args, &mut i)?;
+
+                match fs::read_to_string(&path) {
+                    Ok(token) => config.connection.token = Some(token.trim().to_string()),
+                    Err(e) => {
+                        return Err(UsageError::Invalid(format!(
+                            "Could not read token file {}: {}",
+                            path, e
+                        )));
+                    }
+                }
+            }
+            arg if !arg.starts_with('-') && positional.is_none() => {
+                positional = Some(arg.to_string())
+            }
+            arg => {
+                return Err(UsageError::Invalid(format!("Unknown argument: {}", arg)));
+            }
+        }
+
+        i += 1;
+    }
+
+    // The pager reads single key presses, which needs raw mode.
+    if config.no_raw {
+        config.pager = Pager::Off;
+    }
+
+    if let Some(address) = positional {
+        if !addresses.is_empty() {
+            return Err(UsageError::Invalid(
+                "Give the address either as an argument or with --address, not both".to_string(),
+            ));
+        }
+
+        addresses = split_addresses(&address);
+    }
+
+    if !addresses.is_empty() {
+        config.addresses = addresses;
+    }
+
+    Ok(config)
+}
+
+fn split_addresses(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn escape_history(command: &str) -> String {
+    command.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_history(line: &str) -> String {
+    let mut command: String = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => command.push('\n'),
+                Some(other) => command.push(other),
+                None => command.push('\\'),
+            }
+        } else {
+            command.push(c);
+        }
+    }
+
+    command
+}
+
+fn load_history(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(unescape_history)
+            .collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            warn!("Could not read history file {}: {}", path.display(), e);
+
+            Vec::new()
+        }
+    }
+}
+
+fn push_history(history: &mut Vec<String>, command: &str, dedup_all: bool) -> bool {
+    if command.trim().is_empty() || history.last().is_some_and(|last| last == command) {
+        return false;
+    }
+
+    if dedup_all {
+        history.retain(|entry| entry != command);
+    }
+
+    history.push(command.to_string());
+
+    true
+}
+
+fn trim_history(history: &mut Vec<String>, max_size: usize) -> bool {
+    if max_size == 0 || history.len() <= max_size {
+        return false;
+    }
+
+    history.drain(..history.len() - max_size);
+
+    true
+}
+
+fn save_history(path: &Path, history: &[String]) {
+    let contents: String = history
+        .iter()
+        .map(|command| format!("{}\n", escape_history(command)))
+        .collect();
+
+    if let Err(e) = fs::write(path, contents) {
+        warn!("Could not write history file {}: {}", path.display(), e);
+    }
+}
+
+fn append_history(path: &Path, command: &str) {
+    let result: io::Result<()> = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", escape_history(command)));
+
+    if let Err(e) = result {
+        warn!("Could not write history file {}: {}", path.display(), e);
+    }
+}
+
+fn format_output(
+    command: &str,
+    output: &str,
+    success: bool,
+    elapsed: Duration,
+    format: Format,
+) -> String {
+    match format {
+        Format::Text if success => paint(output, Tone::Output),
+        Format::Text => paint(output, Tone::Error),
+        Format::Json => serde_json::to_string(&JsonRecord {
+            command,
+            output,
+            success,
+            elapsed_ms: elapsed.as_millis(),
+        })
+        .unwrap_or_default(),
+        Format::Csv => render_csv(output),
+    }
+}
+
+// Splits rows on tabs, or failing that on pipes, and only succeeds when every
+// row has the same number of cells.
+fn split_rows(text: &str) -> Option<Vec<Vec<&str>>> {
+    let lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+
+    if lines.is_empty() {
+        return None;
+    }
+
+    let delimiter: char = if lines.iter().all(|line| line.contains('\t')) {
+        '\t'
+    } else if lines.iter().all(|line| line.contains('|')) {
+        '|'
+    } else {
+        return None;
+    };
+
+    let rows: Vec<Vec<&str>> = lines
+        .iter()
+        .map(|line| {
+            let line: &str = match delimiter {
+                '|' => line.trim().trim_start_matches('|').trim_end_matches('|'),
+                _ => line,
+            };
+
+            line.split(delimiter).map(str::trim).collect()
+        })
+        .collect();
+
+    let columns: usize = rows[0].len();
+
+    if rows.iter().any(|row| row.len() != columns) {
+        return None;
+    }
+
+    Some(rows)
+}
+
+// Anything that doesn't split into rows is printed as it came.
+fn render_table(text: &str, style: TableStyle) -> Option<String> {
+    let rows: Vec<Vec<&str>> = split_rows(text).filter(|rows| rows.len() >= 2)?;
+    let columns: usize = rows[0].len();
+
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| rows.iter().map(|row| row[i].width()).max().unwrap_or(0))
+        .collect();
+
+    let format_row = |row: &[&str]| -> String {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{}{}", cell, " ".repeat(width - cell.width())))
+            .collect();
+
+        match style {
+            TableStyle::Ascii | TableStyle::Markdown => format!("| {} |", cells.join(" | ")),
+            TableStyle::Borderless => cells.join("  ").trim_end().to_string(),
+        }
+    };
+
+    let rule = |edge: &str, joint: &str| -> String {
+        let dashes: Vec<String> = widths.iter().map(|width| "-".repeat(width + 2)).collect();
+
+        format!("{}{}{}", edge, dashes.join(joint), edge)
+    };
+
+    let mut table: Vec<String> = Vec::with_capacity(rows.len() + 3);
+
+    match style {
+        TableStyle::Ascii => table.push(rule("+", "+")),
+        TableStyle::Markdown | TableStyle::Borderless => {}
+    }
+
+    table.push(format_row(&rows[0]));
+
+    match style {
+        TableStyle::Ascii => table.push(rule("+", "+")),
+        TableStyle::Markdown => table.push(rule("|", "|")),
+        TableStyle::Borderless => {
+            let dashes: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+
+            table.push(dashes.join("  "));
+        }
+    }
+
+    table.extend(rows[1..].iter().map(|row| format_row(row)));
+
+    if let TableStyle::Ascii = style {
+        table.push(rule("+", "+"));
+    }
+
+    Some(table.join("\n"))
+}
+
+// RFC 4180 records. Output that doesn't split into rows is kept one line per
+// record, so it still round-trips through a CSV reader.
+fn render_csv(text: &str) -> String {
+    let rows: Vec<Vec<&str>> = split_rows(text).unwrap_or_else(|| {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| vec![line])
+            .collect()
+    });
+
+    let field = |value: &&str| -> String {
+        if value.contains([',', '"', '\r', '\n']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    };
+
+    rows.iter()
+        .map(|row| row.iter().map(field).collect::<Vec<String>>().join(","))
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+// A whole reply as one CSV document, every record ended with CRLF.
+fn csv_document(chunks: &[String]) -> String {
+    let rendered: String = render_csv(&chunks.join("\n"));
+
+    if rendered.is_empty() {
+        rendered
+    } else {
+        rendered + "\r\n"
+    }
+}
+
+fn status_hint(code: Code) -> Option<&'static str> {
+    match code {
+        Code::Unauthenticated => Some("check your --token"),
+        Code::PermissionDenied => Some("the token is valid but not allowed to do this"),
+        Code::InvalidArgument => Some("check the command syntax"),
+        Code::DeadlineExceeded => Some("the server took too long; try a longer --timeout"),
+        Code::Unimplemented => Some("the server does not support this command"),
+        Code::ResourceExhausted => Some("the server is overloaded or the reply is too large"),
+        _ => None,
+    }
+}
+
+// Lays out a failed command's status: `context` with the code and message
+// first, then any details and a hint, and with --verbose the response metadata.
+fn status_report(context: &str, status: &Status, verbose: bool) -> Vec<String> {
+    let mut lines: Vec<String> = vec![format!(
+        "{}: {:?}: {}",
+        context,
+        status.code(),
+        status.message()
+    )];
+
+    if !status.details().is_empty() {
+        lines.push(format!(
+            "  details: {}",
+            String::from_utf8_lossy(status.details())
+        ));
+    }
+
+    if let Some(hint) = status_hint(status.code()) {
+        lines.push(format!("  hint: {}", hint));
+    }
+
+    if verbose {
+        for (name, value) in status.metadata().clone().into_headers().iter() {
+            lines.push(format!(
+                "  {}: {}",
+                name,
+                value.to_str().unwrap_or("<binary>")
+            ));
+        }
+    }
+
+    lines
+}
+
+fn is_verbose(config: &Config) -> bool {
+    config.log_level >= Level::DEBUG
+}
+
+fn format_timing(elapsed: Duration) -> String {
+    paint(&format!("({}ms)", elapsed.as_millis()), Tone::Info)
+}
+
+// Heads each command's output in the --output file with when it was sent.
+fn transcribe_command(transcript: &mut BufWriter<File>, command: &str) {
+    let timestamp: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    if let Err(e) = writeln!(transcript, "# [{}] {}", timestamp, command) {
+        warn!("Could not write to the output file: {}", e);
+    }
+}
+
+async fn execute_command(
+    client: &mut LilDbConnection,
+    command: &str,
+    transcript: &mut Option<BufWriter<File>>,
+    config: &Config,
+) -> Result<(), Status> {
+    if is_blocked(command, config) {
+        return Err(Status::failed_precondition(READ_ONLY_BLOCKED));
+    }
+
+    if config.dry_run {
+        println!("[dry-run] {}", command);
+
+        return Ok(());
+    }
+
+    if let Some(transcript) = transcript.as_mut() {
+        transcribe_command(transcript, command);
+    }
+
+    let start: Instant = Instant::now();
+    let mut rows: Vec<String> = Vec::new();
+
+    let result: Result<(), Status> = async {
+        let mut inbound = client.run_command(command).await?;
+
+        while let Some(chunk) = inbound.next().await {
+            let chunk: String = chunk?;
+
+            // CSV is written as one document once the whole reply is in.
+            if let Format::Csv = config.format {
+                rows.push(chunk);
+
+                continue;
+            }
+
+            let output: String =
+                format_output(command, &chunk, true, start.elapsed(), config.format);
+
+            if let Some(transcript) = transcript.as_mut() {
+                if let Err(e) = writeln!(transcript, "{}", chunk) {
+                    warn!("Could not write to the output file: {}", e);
+                }
+            }
+
+            println!("{}", output);
+        }
+
+        Ok(())
+    }
+    .await;
+
+    if !rows.is_empty() {
+        let document: String = csv_document(&rows);
+
+        if let Some(transcript) = transcript.as_mut() {
+            if let Err(e) = write!(transcript, "{}", document) {
+                warn!("Could not write to the output file: {}", e);
+            }
+        }
+
+        print!("{}", document);
+    }
+
+    if let Some(transcript) = transcript.as_mut() {
+        if let Err(e) = transcript.flush() {
+            warn!("Could not write to the output file: {}", e);
+        }
+    }
+
+    match (&result, config.format) {
+        (Err(status), Format::Json) => {
+            let output: String = format_output(
+                command,
+                status.message(),
+                false,
+                start.elapsed(),
+                config.format,
+            );
+
+            println!("{}", output);
+        }
+        (Ok(()), Format::Text) if config.timing => println!("{}", format_timing(start.elapsed())),
+        _ => {}
+    }
+
+    result
+}
+
+async fn run_script<R: BufRead>(
+    client: &mut LilDbConnection,
+    script: R,
+    transcript: &mut Option<BufWriter<File>>,
+    config: &Config,
+) -> Result<bool, Box<dyn Error>> {
+    let mut success: bool = true;
+
+    for (number, line) in script.lines().enumerate() {
+        let line: String = line?;
+        let line: &str = line.trim();
+
+        if line.is_empty()
+            || (line.starts_with('#') && matches!(config.file_format, FileFormat::Plain))
+        {
+            continue;
+        }
+
+        let entry: ScriptEntry = match config.file_format {
+            FileFormat::Plain => ScriptEntry {
+                command: line.to_string(),
+                expect_success: None,
+            },
+            FileFormat::Jsonl => match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!("Line {}: malformed JSON: {}", number + 1, e);
+
+                    success = false;
+
+                    if !config.continue_on_error {
+                        break;
+                    }
+
+                    continue;
+                }
+            },
+        };
+
+        let result: Result<(), Status> =
+            execute_command(client, &entry.command, transcript, config).await;
+
+        let failed: bool = match (&result, entry.expect_success) {
+            (Ok(()), Some(false)) if !config.dry_run => {
+                error!(
+                    "Line {}: command succeeded but was expected to fail",
+                    number + 1
+                );
+
+                true
+            }
+            (Err(status), Some(true) | None) => {
+                let context: String = format!("Line {}: command failed", number + 1);
+
+                for line in status_report(&context, status, is_verbose(config)) {
+                    error!("{}", line);
+                }
+
+                true
+            }
+            _ => false,
+        };
+
+        if failed {
+            success = false;
+
+            if !config.continue_on_error {
+                break;
+            }
+        }
+    }
+
+    Ok(success)
+}
+
+async fn run_batch(
+    client: &mut LilDbConnection,
+    transcript: &mut Option<BufWriter<File>>,
+    config: &Config,
+) -> bool {
+    if let Some(command) = &config.command {
+        return match execute_command(client, command, transcript, config).await {
+            Ok(()) => true,
+            Err(status) => {
+                for line in status_report("Command failed", &status, is_verbose(config)) {
+                    error!("{}", line);
+                }
+
+                false
+            }
+        };
+    }
+
+    let result: Result<bool, Box<dyn Error>> = match &config.file {
+        Some(path) => match File::open(path) {
+            Ok(file) => run_script(client, BufReader::new(file), transcript, config).await,
+            Err(e) => Err(e.into()),
+        },
+        None => run_script(client, io::stdin().lock(), transcript, config).await,
+    };
+
+    match result {
+        Ok(success) => success,
+        Err(e) => {
+            error!("Could not run commands: {}", e);
+
+            false
+        }
+    }
+}
+
+async fn run_init(
+    client: &mut LilDbConnection,
+    session: &mut Session,
+    config: &Config,
+) -> Result<bool, Box<dyn Error>> {
+    let path: PathBuf = match &config.init {
+        Some(path) => path.clone(),
+        None => home_dir().join(".config/lildbsh/init.lildb"),
+    };
+
+    let script: String = match fs::read_to_string(&path) {
+        Ok(script) => script,
+        Err(e) if e.kind() == ErrorKind::NotFound && config.init.is_none() => return Ok(true),
+        Err(e) => return Err(format!("could not read {}: {}", path.display(), e).into()),
+    };
+
+    source_script(
+        client,
+        &path,
+        &script,
+        session,
+        config.strict_init,
+        config.quiet,
+        config,
+    )
+    .await
+}
+
+// Runs each non-comment line of a script the way a typed command runs,
+// reporting failures by line number. Returns false as soon as a line fails
+// when strict; a lost connection is passed up.
+async fn source_script(
+    client: &mut LilDbConnection,
+    path: &Path,
+    script: &str,
+    session: &mut Session,
+    strict: bool,
+    quiet: bool,
+    config: &Config,
+) -> Result<bool, Box<dyn Error>> {
+    for (number, line) in script.lines().enumerate() {
+        let line: &str = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (command, redirect): (&str, Option<Redirect>) = split_redirect(line);
+
+        if is_blocked(command, config) {
+            let message: String = format!(
+                "{} line {}: {}",
+                path.display(),
+                number + 1,
+                READ_ONLY_BLOCKED
+            );
+
+            print!("{}\n\r", paint(&message, Tone::Error));
+
+            if strict {
+                return Ok(false);
+            }
+
+            continue;
+        }
+
+        if config.confirm_destructive && starts_with_keyword(command, &config.destructive_keywords)
+        {
+            let question: String = format!(
+                "{} line {} looks destructive. Send it?",
+                path.display(),
+                number + 1
+            );
+
+            if !confirm(&question, !config.no_raw).await? {
+                print!("\n\r{}\n\r", paint("Not sent", Tone::Info));
+
+                continue;
+            }
+        }
+
+        if !run_remote(client, command, session, redirect.as_ref(), quiet, config).await? {
+            let message: String = format!("{} line {}: command failed", path.display(), number + 1);
+
+            print!("{}\n\r", paint(&message, Tone::Error));
+
+            if strict {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+fn print_connected(address: &str, response: &ConnectResponse) {
+    let message: String = format!("{} ({})!", response.message, address);
+
+    print!("{}\n\r", paint(&message, Tone::Info));
+
+    if !response.server_version.is_empty() {
+        let version: String = format!("Server version: {}", response.server_version);
+
+        print!("{}\n\r", paint(&version, Tone::Info));
+    }
+}
+
+fn print_banner(address: &str) {
+    let banner: String = format!(
+        "LilDBsh {} connected to {}",
+        env!("CARGO_PKG_VERSION"),
+        address
+    );
+
+    print!("{}\n\r", paint(&banner, Tone::Info));
+    print!(
+        "{}\n\r",
+        paint("Type .help for commands, exit to quit", Tone::Info)
+    );
+}
+
+async fn reconnect(
+    client: &mut LilDbConnection,
+    address: &str,
+    public_ip: &Arc<PublicIp>,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let notice: String = format!("Connection to {} lost, reconnecting...", address);
+
+    print!("\n\r{}\n\r", paint(&notice, Tone::Info));
+
+    let connecting = LilDbConnection::connect(address, public_ip.clone(), &config.connection);
+
+    let Some(connected) = until_interrupted(connecting, !config.no_raw).await? else {
+        print!("\n\r");
+
+        exit(EXIT_SIGINT);
+    };
+
+    match connected {
+        Ok((new_client, response)) if response.success => {
+            *client = new_client;
+
+            set_active(client);
+
+            print_connected(address, &response);
+
+            Ok(())
+        }
+        Ok((_, response)) => {
+            error!("Reconnection refused: {}", response.message);
+
+            exit(EXIT_REFUSED);
+        }
+        Err(e) => {
+            error!("Failed to reconnect: {}", e);
+
+            exit(EXIT_CONNECT);
+        }
+    }
+}
+
+fn save_output(path: &str, output: &[String]) {
+    let path: String = if path.is_empty() {
+        let timestamp: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+
+        format!("lildb-output-{}.txt", timestamp)
+    } else {
+        path.to_string()
+    };
+
+    let contents: String = output.iter().map(|chunk| format!("{}\n", chunk)).collect();
+
+    match fs::write(&path, contents) {
+        Ok(()) => print!(
+            "{}\n\r",
+            paint(&format!("Saved output to {}", path), Tone::Info)
+        ),
+        Err(e) => {
+            let message: String = format!("Could not write {}: {}", path, e);
+
+            print!("{}\n\r", paint(&message, Tone::Error));
+        }
+    }
+}
+
+fn summarize_timings(timings: &[Duration]) -> String {
+    if timings.is_empty() {
+        return "No commands timed yet\n\r".to_string();
+    }
+
+    let mut sorted: Vec<Duration> = timings.to_vec();
+    sorted.sort();
+
+    // Nearest-rank percentile.
+    let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+    let mean: Duration = sorted.iter().sum::<Duration>() / sorted.len() as u32;
+
+    [
+        ("Count", sorted.len().to_string()),
+        ("Min", format!("{}ms", sorted[0].as_millis())),
+        ("Max", format!("{}ms", sorted[sorted.len() - 1].as_millis())),
+        ("Mean", format!("{}ms", mean.as_millis())),
+        ("p50", format!("{}ms", percentile(50).as_millis())),
+        ("p95", format!("{}ms", percentile(95).as_millis())),
+    ]
+    .iter()
+    .map(|(label, value)| format!("{:<6} {}\n\r", label, value))
+    .collect()
+}
+
+fn run_meta_command(
+    command: &str,
+    command_history: &[String],
+    session: &mut Session,
+    address: &str,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let (name, argument): (&str, &str) = command
+        .split_once(char::is_whitespace)
+        .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+
+    print!("\n\r");
+
+    match name {
+        ".help" => {
+            for (name, description) in META_COMMANDS {
+                print!("{:<10} {}\n\r", name, description);
+            }
+
+            print!("Start a line with \\ to send it as is, e.g. \\exit or \\.help\n\r");
+            print!("End a line with > file or >> file to save its output; >| out redirects to any name\n\r");
+        }
+        ".clear" => queue!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?,
+        ".history" => {
+            for (i, entry) in command_history.iter().enumerate() {
+                print!("{:>4}  {}\n\r", i + 1, entry.replace('\n', "\n\r      "));
+            }
+        }
+        ".save" => save_output(argument, &session.last_output),
+        ".stats" => {
+            let elapsed: u64 = session.started.elapsed().as_secs();
+
+            print!("Commands run:    {}\n\r", session.commands);
+            print!(
+                "Session length:  {}h {:02}m {:02}s\n\r",
+                elapsed / 3600,
+                elapsed / 60 % 60,
+                elapsed % 60
+            );
+            print!("Output received: {} bytes\n\r", session.received);
+        }
+        ".alias" => {
+            if config.aliases.is_empty() {
+                print!("No aliases; add them under [alias] in the config file\n\r");
+            }
+
+            for (name, body) in &config.aliases {
+                print!("{} = {}\n\r", name, body);
+            }
+        }
+        ".timings" if argument == "reset" => {
+            session.timings.clear();
+
+            print!("Timings cleared\n\r");
+        }
+        ".timings" => print!("{}", summarize_timings(&session.timings)),
+        ".status" => {
+            let options: &ConnectOptions = &config.connection;
+            let elapsed: u64 = session.started.elapsed().as_secs();
+
+            let tls: bool = options.tls || address.starts_with("https://");
+
+            let keepalive: String = match (options.keepalive, options.keepalive_interval) {
+                (false, _) => "off".to_string(),
+                (true, Some(interval)) => format!(
+                    "ping every {}s, {}s timeout",
+                    interval, options.keepalive_timeout
+                ),
+                (true, None) => format!("no pings, {}s timeout", options.keepalive_timeout),
+            };
+
+            print!("Server:          {}\n\r", address);
+            print!("TLS:             {}\n\r", if tls { "on" } else { "off" });
+            print!("Keepalive:       {}\n\r", keepalive);
+            print!("Commands run:    {}\n\r", session.commands);
+            print!(
+                "Session length:  {}h {:02}m {:02}s\n\r",
+                elapsed / 3600,
+                elapsed / 60 % 60,
+                elapsed % 60
+            );
+        }
+        _ => {
+            let message: String = format!("Unknown meta-command {}, type .help for a list", name);
+
+            print!("{}\n\r", paint(&message, Tone::Error));
+        }
+    }
+
+    stdout().flush()?;
+
+    Ok(())
+}
+
+fn show_paged(output: &[String], pager: Pager) -> Result<(), Box<dyn Error>> {
+    let lines: Vec<&str> = output.iter().flat_map(|chunk| chunk.lines()).collect();
+
+    let page: usize = match pager {
+        Pager::Off => usize::MAX,
+        Pager::Auto | Pager::Always => {
+            let (_, height): (u16, u16) = terminal::size()?;
+
+            (height as usize).saturating_sub(1).max(1)
+        }
+    };
+
+    if let Pager::Off | Pager::Auto = pager {
+        if lines.len() <= page {
+            for line in &lines {
+                print!("{}\n\r", line);
+            }
+
+            return Ok(());
+        }
+    }
+
+    let mut shown: usize = 0;
+    let mut limit: usize = page;
+
+    while shown < lines.len() {
+        while shown < limit.min(lines.len()) {
+            print!("{}\n\r", lines[shown]);
+
+            shown += 1;
+        }
+
+        if shown == lines.len() {
+            break;
+        }
+
+        print!(
+            "{}",
+            paint(
+                "-- More -- (space: next page, enter: next line, q: quit)",
+                Tone::Info
+            )
+        );
+
+        stdout().flush()?;
+
+        let code: KeyCode = loop {
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = read()?
+            {
+                break code;
+            }
+        };
+
+        queue!(stdout(), MoveToColumn(0), Clear(ClearType::UntilNewLine))?;
+
+        match code {
+            KeyCode::Char(' ') | KeyCode::PageDown => limit = shown + page,
+            KeyCode::Enter | KeyCode::Down => limit = shown + 1,
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            _ => {}
+        }
+    }
+
+    stdout().flush()?;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    Exit,
+    Connect(String),
+    Ping,
+    Describe(String),
+    ClearHistory,
+    Source(String, bool),
+    Meta(String),
+    Remote(String),
+}
+
+// Expands !!, .repeat and !<n> into the history entry they refer to, numbered
+// as in .history; anything else is returned unchanged.
+fn expand_history(command: &str, history: &[String]) -> Result<String, String> {
+    if command == "!!" || command == ".repeat" {
+        return history
+            .last()
+            .cloned()
+            .ok_or_else(|| "No previous command to repeat".to_string());
+    }
+
+    match command.strip_prefix('!').map(str::parse::<usize>) {
+        Some(Ok(n)) => match n.checked_sub(1).and_then(|i| history.get(i)) {
+            Some(entry) => Ok(entry.clone()),
+            None => Err(format!(
+                "No history entry {}, there are {}",
+                n,
+                history.len()
+            )),
+        },
+        _ => Ok(command.to_string()),
+    }
+}
+
+// Turns CRLF into LF and drops trailing whitespace from every line, leaving
+// spacing inside a line alone.
+fn normalize_command(command: &str) -> String {
+    command
+        .replace("\r\n", "\n")
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+// Blank input, such as a recalled entry of only spaces or newlines, is never
+// sent, even with --no-trim; the server would take it as a ping.
+fn is_blank(command: &str) -> bool {
+    command.trim().is_empty()
+}
+
+// Replaces a leading alias name with its body, keeping any arguments after it.
+// Each alias is expanded at most once, so aliases that refer to each other
+// stop instead of looping.
+fn expand_alias(command: &str, aliases: &BTreeMap<String, String>) -> String {
+    let mut command: String = command.to_string();
+    let mut expanded: Vec<&str> = Vec::new();
+
+    loop {
+        let end: usize = command.find(char::is_whitespace).unwrap_or(command.len());
+
+        match aliases.get_key_value(&command[..end]) {
+            Some((name, body)) if !expanded.contains(&name.as_str()) => {
+                expanded.push(name);
+
+                command = format!("{}{}", body, &command[end..]);
+            }
+            _ => return command,
+        }
+    }
+}
+
+// A leading backslash sends the rest of the line verbatim, so exit and
+// dot-commands can still reach the server (\exit sends exit).
+fn classify(command: String, interrupted: bool) -> Input {
+    if interrupted {
+        Input::Exit
+    } else if let Some(literal) = command.strip_prefix('\\') {
+        Input::Remote(literal.to_string())
+    } else if command == "exit" || command == ".exit" {
+        Input::Exit
+    } else if command == ".connect" || command.starts_with(".connect ") {
+        Input::Connect(command[".connect".len()..].trim().to_string())
+    } else if let Some(argument) = command.strip_prefix(".source!") {
+        Input::Source(argument.trim().to_string(), true)
+    } else if command == ".source" || command.starts_with(".source ") {
+        Input::Source(command[".source".len()..].trim().to_string(), false)
+    } else if command == ".ping" {
+        Input::Ping
+    } else if command == ".clearhistory" {
+        Input::ClearHistory
+    } else if command == ".describe" || command.starts_with(".describe ") {
+        Input::Describe(command[".describe".len()..].trim().to_string())
+    } else if command.starts_with('.') {
+        Input::Meta(command)
+    } else {
+        Input::Remote(command)
+    }
+}
+
+// Line-mode input for --no-raw: the terminal does the editing, and None means
+// stdin was closed.
+fn read_line(prompt: &str) -> io::Result<Option<String>> {
+    print!("{}", paint(prompt, Tone::Prompt));
+
+    stdout().flush()?;
+
+    let mut line: String = String::new();
+
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+async fn read_command(
+    history: &[String],
+    scrollback: &[String],
+    prompt: &Prompt,
+    idle: Option<Duration>,
+    draft: String,
+    raw: bool,
+) -> Result<Option<(String, bool)>, Box<dyn Error>> {
+    if !raw {
+        let prompt: String = prompt.render();
+
+        let reading = tokio::task::spawn_blocking(move || read_line(&prompt));
+
+        // The blocking read cannot be stopped, so --idle-timeout gives up on
+        // it instead; the caller disconnects and the process exits under it.
+        let line: Option<String> = match idle {
+            Some(idle) => match tokio::time::timeout(idle, reading).await {
+                Ok(line) => line??,
+                Err(_) => return Ok(None),
+            },
+            None => reading.await??,
+        };
+
+        // End of input behaves like Ctrl+C on an empty line.
+        return Ok(Some(
+            line.map_or((String::new(), true), |line| (line, false)),
+        ));
+    }
+
+    let history: Vec<String> = history.to_vec();
+    let scrollback: Vec<String> = scrollback.to_vec();
+    let prompt: Prompt = prompt.clone();
+
+    let result: Result<Option<(String, bool)>, String> = tokio::task::spawn_blocking(move || {
+        let mut command: String = draft;
+
+        let interrupted: Option<bool> =
+            read_input(&mut command, &history, &scrollback, &prompt, idle)
+                .map_err(|e| e.to_string())?;
+
+        Ok(interrupted.map(|interrupted| (command, interrupted)))
+    })
+    .await?;
+
+    Ok(result?)
+}
+
+fn starts_with_keyword(command: &str, keywords: &[String]) -> bool {
+    let first: &str = command.split_whitespace().next().unwrap_or_default();
+
+    keywords
+        .iter()
+        .any(|keyword| keyword.eq_ignore_ascii_case(first))
+}
+
+// --read-only is a client-side guardrail against typos, not security: it only
+// looks at the first word, and the server enforces nothing.
+fn is_blocked(command: &str, config: &Config) -> bool {
+    config.read_only && starts_with_keyword(command, &config.write_keywords)
+}
+
+// Asks a y/N question, answering no to anything but y.
+async fn confirm(question: &str, raw: bool) -> Result<bool, Box<dyn Error>> {
+    print!("\n\r{} [y/N] ", paint(question, Tone::Prompt));
+
+    stdout().flush()?;
+
+    let answer: Option<String> = tokio::task::spawn_blocking(move || -> io::Result<_> {
+        if !raw {
+            return read_line("");
+        }
+
+        loop {
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = read()?
+            {
+                let answer: String = match code {
+                    KeyCode::Char(c) => c.to_string(),
+                    _ => String::new(),
+                };
+
+                print!("{}", answer);
+
+                return Ok(Some(answer));
+            }
+        }
+    })
+    .await??;
+
+    Ok(answer.is_some_and(|answer| answer.trim().eq_ignore_ascii_case("y")))
+}
+
+fn persist_history(history: &mut Vec<String>, command: &str, config: &Config) {
+    let trimmed: bool = trim_history(history, config.history_size);
+
+    if config.history_dedup_all || trimmed {
+        save_history(&config.history_file, history);
+    } else {
+        append_history(&config.history_file, command);
+    }
+}
+
+// `kept_file` is set once .clearhistory has emptied the session's history but
+// kept the file; rewrites then start from the file's own entries, so the kept
+// ones are not replaced by the shorter in-memory list.
+fn record_history(
+    command_history: &mut Vec<String>,
+    command: &str,
+    kept_file: bool,
+    config: &Config,
+) {
+    if config.no_history || !push_history(command_history, command, config.history_dedup_all) {
+        return;
+    }
+
+    if !kept_file {
+        persist_history(command_history, command, config);
+
+        return;
+    }
+
+    trim_history(command_history, config.history_size);
+
+    let mut saved: Vec<String> = load_history(&config.history_file);
+
+    if push_history(&mut saved, command, config.history_dedup_all) {
+        persist_history(&mut saved, command, config);
+    }
+}
+
+struct Redirect {
+    path: String,
+    append: bool,
 }
 
-fn read_input(input: &mut String) -> Result<bool, Box<dyn Error>> {
-    print!(">> ");
-    stdout().flush()?;
+// File extensions a redirect target may end in when it has no `/`.
+const REDIRECT_EXTENSIONS: [&str; 9] = [
+    "txt", "csv", "tsv", "json", "jsonl", "log", "out", "md", "sql",
+];
+
+// A redirect target has to look like a file, so comparisons such as
+// `age > 30` or `a > b.id` still reach the server.
+fn looks_like_path(target: &str) -> bool {
+    if target.starts_with('=') {
+        return false;
+    }
+
+    target.contains('/')
+        || target.rsplit_once('.').is_some_and(|(stem, extension)| {
+            !stem.is_empty()
+                && REDIRECT_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+// Splits a trailing `> file` or `>> file` off a command. Only a `>` outside
+// quotes, with whitespace before it and a single path-like word after it,
+// counts, so `"a > b"`, `x>=5` and `age > 30` are sent unchanged. `>| file`
+// and `>>| file` redirect to any name, for files the path check would miss.
+fn split_redirect(command: &str) -> (&str, Option<Redirect>) {
+    let mut quote: Option<char> = None;
+    let mut last: Option<usize> = None;
+
+    for (i, c) in command.char_indices() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => last = Some(i),
+            _ => {}
+        }
+    }
+
+    let Some(last) = last else {
+        return (command, None);
+    };
+
+    let target: &str = &command[last + 1..];
+    let forced: bool = target.starts_with('|');
+    let path: &str = target[usize::from(forced)..].trim();
+    let append: bool = command[..last].ends_with('>');
+    let before: &str = &command[..last - usize::from(append)];
+    let rest: &str = before.trim_end();
+
+    if quote.is_some()
+        || !before.ends_with(char::is_whitespace)
+        || path.is_empty()
+        || path.contains(char::is_whitespace)
+        || !(forced || looks_like_path(path))
+        || rest.is_empty()
+    {
+        return (command, None);
+    }
+
+    (
+        rest,
+        Some(Redirect {
+            path: path.to_string(),
+            append,
+        }),
+    )
+}
+
+// The terminal is in raw mode while a command runs, so Ctrl+C arrives as a key
+// event rather than SIGINT; poll for it until the command finishes. Without raw
+// mode Ctrl+C is a signal, and polling would only swallow typed-ahead lines.
+fn watch_interrupt(stop: Arc<AtomicBool>, raw: bool) -> JoinHandle<bool> {
+    tokio::task::spawn_blocking(move || {
+        while raw && !stop.load(Ordering::Relaxed) {
+            if !poll(Duration::from_millis(50)).unwrap_or(false) {
+                continue;
+            }
+
+            if let Ok(Event::Key(KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers,
+                kind: KeyEventKind::Press,
+                ..
+            })) = read()
+            {
+                if modifiers.contains(KeyModifiers::CONTROL) {
+                    return true;
+                }
+            }
+        }
+
+        false
+    })
+}
+
+// Sends one command and shows its reply, returning whether it succeeded. A
+// quiet run still records and saves the reply but does not display it.
+// Runs `future` unless Ctrl+C comes first, giving None then. Raw mode turns
+// Ctrl+C into a key press rather than SIGINT, so it is watched for as a key;
+// without raw mode the signal handler already covers it.
+async fn until_interrupted<T>(
+    future: impl Future<Output = T>,
+    raw: bool,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let stop: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let mut watcher: JoinHandle<bool> = watch_interrupt(stop.clone(), raw);
+
+    let outcome: Option<T> = tokio::select! {
+        value = future => Some(value),
+        Ok(true) = &mut watcher, if raw => None,
+    };
+
+    if outcome.is_some() {
+        stop.store(true, Ordering::Relaxed);
+
+        watcher.await?;
+    }
+
+    Ok(outcome)
+}
+
+async fn run_remote(
+    client: &mut LilDbConnection,
+    command: &str,
+    session: &mut Session,
+    redirect: Option<&Redirect>,
+    quiet: bool,
+    config: &Config,
+) -> Result<bool, Box<dyn Error>> {
+    if config.dry_run {
+        let echo: String = format!("[dry-run] {}", command);
+
+        print!("\n\r{}\n\r", paint(&echo, Tone::Info));
+
+        return Ok(true);
+    }
+
+    let mut redirect: Option<(&str, File)> = match redirect {
+        Some(redirect) => {
+            let opened: io::Result<File> = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(redirect.append)
+                .truncate(!redirect.append)
+                .open(&redirect.path);
+
+            match opened {
+                Ok(file) => Some((&redirect.path, file)),
+                Err(e) => {
+                    let message: String = format!("Could not open {}: {}", redirect.path, e);
+
+                    print!("\n\r{}\n\r", paint(&message, Tone::Error));
+
+                    return Ok(false);
+                }
+            }
+        }
+        None => None,
+    };
+
+    session.last_output.clear();
+
+    if let Some(transcript) = session.transcript.as_mut() {
+        transcribe_command(transcript, command);
+    }
+
+    let start: Instant = Instant::now();
+    let mut pending: Vec<String> = Vec::new();
+    let mut numbers: LineNumbers = LineNumbers::new();
+    let mut received: usize = 0;
+    let mut truncated: bool = false;
+    let mut closed: bool = false;
+
+    let stop: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let mut watcher: JoinHandle<bool> = watch_interrupt(stop.clone(), !config.no_raw);
+
+    let mut spinner: Option<Spinner> = if config.no_spinner || !stdout().is_terminal() {
+        None
+    } else {
+        Some(Spinner::start())
+    };
+
+    let mut out: BufWriter<Stdout> = BufWriter::with_capacity(OUTPUT_BUFFER, stdout());
+
+    let request = async {
+        let mut inbound = client.run_command(command).await?;
+
+        loop {
+            let next: Option<Result<String, Status>> =
+                match tokio::time::timeout(FLUSH_AFTER, inbound.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        out.flush()?;
+
+                        inbound.next().await
+                    }
+                };
+
+            let Some(chunk) = next else {
+                break;
+            };
+
+            let mut chunk: String = chunk?;
+
+            if let Some(spinner) = spinner.take() {
+                spinner.stop().await;
+            }
+
+            if let Some(limit) = config.max_output_lines {
+                if received + chunk.lines().count() > limit {
+                    let kept: Vec<&str> = chunk.lines().take(limit - received).collect();
+
+                    chunk = kept.join("\n");
+                    truncated = true;
+                }
+
+                received += chunk.lines().count();
+
+                if truncated && chunk.is_empty() {
+                    break;
+                }
+            }
+
+            let text: String = match config.format {
+                Format::Text if config.line_numbers => numbers.number(&chunk),
+                _ => chunk.clone(),
+            };
+
+            // CSV is written as one document once the whole reply is in.
+            if !matches!(config.format, Format::Csv) {
+                let output: String =
+                    format_output(command, &text, true, start.elapsed(), config.format);
+
+                if let Some((path, file)) = redirect.as_mut() {
+                    if let Err(e) = writeln!(file, "{}", chunk) {
+                        warn!("Could not write to {}: {}", path, e);
+                    }
+                }
+
+                if let Some(transcript) = session.transcript.as_mut() {
+                    if let Err(e) = writeln!(transcript, "{}", chunk) {
+                        warn!("Could not write to the output file: {}", e);
+                    }
+                }
+
+                match (config.table, config.format, config.pager) {
+                    _ if redirect.is_some() || quiet => {}
+                    // Column widths depend on every row, so tables are drawn once
+                    // the whole result is in.
+                    (true, Format::Text, _) => {}
+                    (_, _, Pager::Off) if !config.no_stream => write!(out, "\n\r{}\n\r", output)?,
+                    // With --no-stream nothing is shown until the reply is complete.
+                    (_, _, _) => pending.push(output),
+                }
+            }
+
+            // An empty reply is the server ending the session.
+            if chunk.is_empty() {
+                closed = true;
+
+                break;
+            }
+
+            session.received += chunk.len();
+            session.last_output.push(chunk);
+
+            if truncated {
+                break;
+            }
+        }
+
+        Ok(())
+    };
+
+    let deadline = async {
+        match config.timeout {
+            Some(timeout) => tokio::time::sleep(Duration::from_millis(timeout)).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let mut timed_out: bool = false;
+
+    let result: Option<Result<(), Status>> = tokio::select! {
+        result = request => Some(result),
+        // Without raw mode the watcher returns at once, and a handle polled
+        // here cannot be awaited again below.
+        Ok(true) = &mut watcher, if !config.no_raw => None,
+        () = deadline => {
+            timed_out = true;
+
+            None
+        }
+    };
+
+    let elapsed: Duration = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+
+    out.flush()?;
+
+    // Rows split across messages and the tabular check both need the whole
+    // reply, the same as a table.
+    if let (Format::Csv, false) = (config.format, session.last_output.is_empty()) {
+        let document: String = csv_document(&session.last_output);
+
+        if let Some((path, file)) = redirect.as_mut() {
+            if let Err(e) = write!(file, "{}", document) {
+                warn!("Could not write to {}: {}", path, e);
+            }
+        }
+
+        if let Some(transcript) = session.transcript.as_mut() {
+            if let Err(e) = write!(transcript, "{}", document) {
+                warn!("Could not write to the output file: {}", e);
+            }
+        }
+
+        if redirect.is_none() && !quiet {
+            match config.pager {
+                Pager::Off => print!("\n\r{}", document),
+                Pager::Auto | Pager::Always => pending.push(document),
+            }
+        }
+    }
+
+    if let Some(transcript) = session.transcript.as_mut() {
+        if let Err(e) = transcript.flush() {
+            warn!("Could not write to the output file: {}", e);
+        }
+    }
+
+    if let Some(spinner) = spinner {
+        spinner.stop().await;
+    }
+
+    if let (true, Format::Text, None, false) = (config.table, config.format, &redirect, quiet) {
+        if !session.last_output.is_empty() {
+            let text: String = session.last_output.join("\n");
+            let rendered: String = render_table(&text, config.table_style).unwrap_or(text);
+
+            let lines: Vec<String> = rendered
+                .lines()
+                .map(|line| paint(line, Tone::Output))
+                .collect();
+
+            match config.pager {
+                Pager::Off => print!("\n\r{}\n\r", lines.join("\n\r")),
+                Pager::Auto | Pager::Always => pending.push(lines.join("\n")),
+            }
+        }
+    }
+
+    if result.is_some() || timed_out {
+        watcher.await?;
+    }
+
+    if !pending.is_empty() {
+        print!("\n\r");
+
+        show_paged(&pending, config.pager)?;
+    }
+
+    if truncated {
+        let notice: String = format!(
+            "[truncated after {} lines]",
+            config.max_output_lines.unwrap_or_default()
+        );
+
+        print!("{}\n\r", paint(&notice, Tone::Info));
+    }
+
+    if closed {
+        if let Err(e) = client.disconnect().await {
+            warn!("Failed to disconnect: {}", e);
+        }
+
+        exit(0);
+    }
+
+    if result.is_some() {
+        session.timings.push(elapsed);
+    }
+
+    let Some(result) = result else {
+        if timed_out {
+            let message: String = format!(
+                "Command timed out after {}ms",
+                config.timeout.unwrap_or_default()
+            );
+
+            print!("\n\r{}\n\r", paint(&message, Tone::Error));
+        } else {
+            print!("\n\r{}\n\r", paint("Command cancelled", Tone::Info));
+        }
+
+        return Ok(false);
+    };
+
+    if let (Ok(()), Some((path, _)), false) = (&result, &redirect, quiet) {
+        let message: String = format!("Wrote output to {}", path);
+
+        print!("\n\r{}\n\r", paint(&message, Tone::Info));
+    }
+
+    if let (Ok(()), Format::Text) = (&result, config.format) {
+        if config.timing && !quiet {
+            print!("{}\n\r", format_timing(elapsed));
+        }
+    }
+
+    // Whatever arrived before the error has been shown by now. Only a lost
+    // connection is passed up, so the shell can reconnect; any other status
+    // ends just this command.
+    match result {
+        Err(status)
+            if status.code() != Code::Unavailable && matches!(config.format, Format::Json) =>
+        {
+            let record: String =
+                format_output(command, status.message(), false, elapsed, config.format);
+
+            print!("\n\r{}\n\r", record);
+
+            Ok(false)
+        }
+        Err(status) if status.code() != Code::Unavailable => {
+            print!("\n\r");
+
+            for line in status_report("Command ended with error", &status, is_verbose(config)) {
+                print!("{}\n\r", paint(&line, Tone::Error));
+            }
+
+            Ok(false)
+        }
+        result => {
+            result?;
+
+            Ok(true)
+        }
+    }
+}
+
+// Sends the configured describe command for `name` and lays the reply out as
+// a table when it splits into rows, printing it unchanged otherwise.
+async fn describe(client: &mut LilDbConnection, name: &str, config: &Config) -> Result<(), Status> {
+    let command: String = config.describe_command.replace("{}", name);
+
+    if config.dry_run {
+        print!(
+            "{}\n\r",
+            paint(&format!("[dry-run] {}", command), Tone::Info)
+        );
+
+        return Ok(());
+    }
+
+    let mut inbound = client.run_command(&command).await?;
+    let mut chunks: Vec<String> = Vec::new();
+
+    while let Some(chunk) = inbound.next().await {
+        chunks.push(chunk?);
+    }
+
+    let text: String = chunks.join("\n");
+    let rendered: String = render_table(&text, config.table_style).unwrap_or(text);
+
+    for line in rendered.lines() {
+        print!("{}\n\r", paint(line, Tone::Output));
+    }
+
+    Ok(())
+}
+
+async fn switch_server(
+    client: &mut LilDbConnection,
+    address: &mut String,
+    public_ip: &Arc<PublicIp>,
+    config: &Config,
+    target: &str,
+) -> Result<(), Box<dyn Error>> {
+    print!("\n\r");
+
+    if target.is_empty() {
+        print!("{}\n\r", paint("Usage: .connect <address>", Tone::Error));
+
+        return Ok(());
+    }
+
+    let connecting = LilDbConnection::connect(target, public_ip.clone(), &config.connection);
+
+    let Some(connected) = until_interrupted(connecting, !config.no_raw).await? else {
+        let message: String = format!("Cancelled, still connected to {}", address);
+
+        print!("\n\r{}\n\r", paint(&message, Tone::Info));
+
+        return Ok(());
+    };
+
+    match connected {
+        Ok((new_client, response)) if response.success => {
+            if let Err(e) = client.disconnect().await {
+                warn!("Failed to disconnect from {}: {}", address, e);
+            }
+
+            *client = new_client;
+            *address = target.to_string();
+
+            set_active(client);
+
+            print_connected(address, &response);
+        }
+        Ok((_, response)) => {
+            let message: String = format!(
+                "{} refused the connection, still connected to {}: {}",
+                target, address, response.message
+            );
+
+            print!("{}\n\r", paint(&message, Tone::Error));
+        }
+        Err(e) => {
+            let message: String = format!(
+                "Could not connect to {}, still connected to {}: {}",
+                target, address, e
+            );
+
+            print!("{}\n\r", paint(&message, Tone::Error));
+        }
+    }
+
+    stdout().flush()?;
+
+    Ok(())
+}
+
+async fn handle_shell(
+    client: &mut LilDbConnection,
+    address: &str,
+    public_ip: &Arc<PublicIp>,
+    command_history: &mut Vec<String>,
+    mut session: Session,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut address: String = address.to_string();
+    let mut prompt: Prompt = Prompt::new(&config.prompt, &address);
+
+    let idle: Option<Duration> = config.idle_timeout.map(Duration::from_secs);
+
+    // A line whose connection dropped under it, put back on the prompt after
+    // reconnecting so it can be sent again.
+    let mut draft: String = String::new();
+
+    // Set when .clearhistory empties the session's history but keeps the file.
+    let mut history_kept: bool = false;
+
+    loop {
+        let Some((command, interrupted)): Option<(String, bool)> = read_command(
+            command_history,
+            &session.last_output,
+            &prompt,
+            idle,
+            std::mem::take(&mut draft),
+            !config.no_raw,
+        )
+        .await?
+        else {
+            let message: String = format!(
+                "No input for {}s, disconnecting",
+                idle.unwrap_or_default().as_secs()
+            );
+
+            print!("\n\r{}\n\r", paint(&message, Tone::Info));
+
+            client.disconnect().await?;
+
+            break;
+        };
+
+        let command: String = if config.no_trim {
+            command
+        } else {
+            normalize_command(&command)
+        };
+
+        if is_blank(&command) && !interrupted {
+            print!("\n\r");
+
+            continue;
+        }
+
+        let command: String = match expand_history(&command, command_history) {
+            Ok(expanded) if expanded != command => {
+                print!("\n\r{}", paint(&expanded, Tone::Info));
+
+                expanded
+            }
+            Ok(command) => command,
+            Err(message) => {
+                print!("\n\r{}\n\r", paint(&message, Tone::Error));
+
+                continue;
+            }
+        };
+
+        let command: String = expand_alias(&command, &config.aliases);
+
+        // History keeps the line as typed, so a recalled \exit is still escaped.
+        let typed: String = command.clone();
+
+        match classify(command, interrupted) {
+            Input::Exit => {
+                let disconnection: DisconnectResponse = client.disconnect().await?;
+
+                if disconnection.success {
+                    print!("\n\r{}!\n\r", paint(&disconnection.message, Tone::Info));
+
+                    break;
+                }
+
+                let message: String = format!("Could not disconnect: {}", disconnection.message);
+
+                print!("\n\r{}\n\r", paint(&message, Tone::Error));
+            }
+            Input::Connect(target) => {
+                switch_server(client, &mut address, public_ip, config, &target).await?;
+
+                prompt.address = address.clone();
+            }
+            Input::Ping => {
+                if config.dry_run {
+                    print!("\n\r{}\n\r", paint("[dry-run] ping", Tone::Info));
+
+                    continue;
+                }
+
+                let (message, tone): (String, Tone) = match client.ping().await {
+                    Ok(elapsed) => (
+                        format!("Pong from {} in {}ms", address, elapsed.as_millis()),
+                        Tone::Info,
+                    ),
+                    Err(status) => (
+                        format!("Ping to {} failed: {}", address, status.message()),
+                        Tone::Error,
+                    ),
+                };
+
+                print!("\n\r{}\n\r", paint(&message, tone));
+            }
+            Input::Describe(name) => {
+                print!("\n\r");
+
+                if name.is_empty() {
+                    print!("{}\n\r", paint("Usage: .describe <name>", Tone::Error));
+
+                    continue;
+                }
+
+                if let Err(status) = describe(client, &name, config).await {
+                    let message: String =
+                        format!("Could not describe {}: {}", name, status.message());
+
+                    print!("{}\n\r", paint(&message, Tone::Error));
+                }
+            }
+            Input::ClearHistory => {
+                command_history.clear();
+
+                // The file is only wiped on a yes; otherwise the next session
+                // still sees the old entries.
+                let saved: bool = !config.no_history
+                    && fs::metadata(&config.history_file).is_ok_and(|file| file.len() > 0);
+
+                let message: &str = if !saved {
+                    "History cleared"
+                } else if confirm(
+                    &format!("Also delete {}?", config.history_file.display()),
+                    !config.no_raw,
+                )
+                .await?
+                {
+                    save_history(&config.history_file, command_history);
+
+                    history_kept = false;
+
+                    "History cleared, including the history file"
+                } else {
+                    history_kept = true;
+
+                    "History cleared for this session; the history file was kept"
+                };
+
+                print!("\n\r{}\n\r", paint(message, Tone::Info));
+            }
+            Input::Source(path, strict) => {
+                print!("\n\r");
+
+                if path.is_empty() {
+                    print!("{}\n\r", paint("Usage: .source <path>", Tone::Error));
+
+                    continue;
+                }
+
+                let script: String = match fs::read_to_string(&path) {
+                    Ok(script) => script,
+                    Err(e) => {
+                        let message: String = format!("Could not read {}: {}", path, e);
+
+                        print!("{}\n\r", paint(&message, Tone::Error));
+
+                        continue;
+                    }
+                };
+
+                let path: &Path = Path::new(&path);
+
+                if !source_script(client, path, &script, &mut session, strict, false, config)
+                    .await?
+                {
+                    return Err(format!("{} stopped on a failed command", path.display()).into());
+                }
+            }
+            // Dot-prefixed commands are handled client-side and never reach the server;
+            // new meta-commands go in META_COMMANDS and run_meta_command, or get an
+            // Input variant when they need the connection.
+            Input::Meta(command) => {
+                run_meta_command(&command, command_history, &mut session, &address, config)?
+            }
+            Input::Remote(command) => {
+                record_history(command_history, &typed, history_kept, config);
+
+                let (sent, redirect): (&str, Option<Redirect>) = split_redirect(&command);
+
+                if is_blocked(sent, config) {
+                    print!("\n\r{}\n\r", paint(READ_ONLY_BLOCKED, Tone::Error));
 
-    loop {
-        if let Event::Key(KeyEvent {
-            code,
-            kind,
-            modifiers,
-            state: _,
-        }) = read()?
-        {
-            if kind == KeyEventKind::Press {
-                match (code, modifiers) {
-                    (KeyCode::Enter, KeyModifiers::ALT) => {
-                        print!("\n\r");
-                        input.push('\n');
-                        stdout().flush()?;
-                    }
-                    (KeyCode::Enter, _) => {
-                        if !input.is_empty() {
-                            stdout().flush()?;
-                            break;
-                        }
-                    }
-                    (KeyCode::Backspace, _) if !input.is_empty() => {
-                        input.pop();
-                        print!("\x1B[1D\x1B[K");
-                        stdout().flush()?;
-                    }
-                    (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(true),
-                    (KeyCode::Char(c), _) => {
-                        input.push(c);
-                        print!("{}", c);
-                        stdout().flush()?;
+                    continue;
+                }
+
+                if config.confirm_destructive
+                    && starts_with_keyword(sent, &config.destructive_keywords)
+                    && !confirm("This command looks destructive. Send it?", !config.no_raw).await?
+                {
+                    print!("\n\r{}\n\r", paint("Not sent", Tone::Info));
+
+                    continue;
+                }
+
+                let result: Result<bool, Box<dyn Error>> =
+                    run_remote(client, sent, &mut session, redirect.as_ref(), false, config).await;
+
+                session.commands += 1;
+                prompt.count = session.commands + 1;
+
+                if let Err(e) = result {
+                    let lost: bool = e
+                        .downcast_ref::<Status>()
+                        .is_some_and(|status| status.code() == Code::Unavailable);
+
+                    if config.no_reconnect || !lost {
+                        return Err(e);
                     }
-                    _ => {} // _ => println!("{:?} {:?}", code, modifiers),
+
+                    reconnect(client, &address, public_ip, config).await?;
+
+                    draft = command;
                 }
             }
         }
     }
 
-    Ok(false)
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    enable_raw_mode()?;
+fn is_interactive(config: &Config) -> bool {
+    config.command.is_none() && config.file.is_none() && io::stdin().is_terminal()
+}
 
-    print!("Please insert your LilDB address (no http://):\n\r");
+fn prompt_address(raw: bool) -> Result<String, Box<dyn Error>> {
+    print!("Please insert your LilDB address (e.g. localhost:50051):\n\r");
 
     stdout().flush()?;
 
+    if !raw {
+        return match read_line(DEFAULT_PROMPT)? {
+            Some(line) => Ok(line),
+            None => exit(0),
+        };
+    }
+
     let mut input = String::new();
-    read_input(&mut input)?;
+    let interrupted: Option<bool> =
+        read_input(&mut input, &[], &[], &Prompt::new(DEFAULT_PROMPT, ""), None)?;
 
     print!("\n\r");
 
-    let channel: Channel = Channel::from_shared(format!("http://{}", input))
-        .unwrap()
-        .keep_alive_while_idle(true)
-        .keep_alive_timeout(Duration::from_secs(30))
-        .connect()
-        .await?;
+    // Ctrl+C here means the user changed their mind, not a failed start.
+    if interrupted == Some(true) {
+        exit(0);
+    }
+
+    Ok(input)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    let mut client: LilDbShellClient<Channel> = LilDbShellClient::new(channel);
+    let config: Config = match load_config(&args, &Environment::from_process()) {
+        Ok(config) => config,
+        Err(usage) => usage.exit(),
+    };
 
-    let public_ip: String = reqwest::get("https://api.ipify.org").await?.text().await?;
+    let color: bool = !config.no_color
+        && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && stdout().is_terminal();
 
-    let response: lildb::ConnectResponse = client
-        .connect_to_db(ConnectRequest {
-            ip: public_ip.to_string(),
-        })
-        .await?
-        .into_inner();
+    COLOR.store(color, Ordering::Relaxed);
 
-    if response.success {
-        print!("{}!\n\r", response.message);
-    } else {
-        print!("Failed to connect to\n\r");
+    // File writes are unbuffered, so nothing is lost when a later
+    // process::exit skips destructors.
+    let log_file = match &config.log_file {
+        Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(fmt::layer().with_ansi(false).with_writer(Mutex::new(file))),
+            Err(e) => {
+                eprintln!("Could not open log file {}: {}", path.display(), e);
 
-        process::exit(1);
+                exit(EXIT_USAGE);
+            }
+        },
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::from_level(config.log_level))
+        .with(
+            fmt::layer()
+                .with_ansi(color)
+                .with_writer(|| RawLines(stderr())),
+        )
+        .with(log_file)
+        .init();
+
+    if config.connection.insecure {
+        warn!("INSECURE: --insecure turns off TLS certificate verification. Anyone on the network path can pose as the server. Never use it outside testing.");
     }
 
-    loop {
-        let (tx, rx): (Sender<CommandRequest>, Receiver<CommandRequest>) = mpsc::channel(4);
-        let (tx_disconnect, mut rx_disconnect): (Sender<bool>, Receiver<bool>) = mpsc::channel(4);
+    if config.read_only {
+        info!("Read-only mode: write commands are blocked by this shell only; the server does not enforce it");
+    }
 
-        tokio::spawn(async move {
-            let mut command = String::new();
+    let interactive: bool = is_interactive(&config);
 
-            let mut exit: bool = read_input(&mut command).unwrap();
+    let mut transcript: Option<BufWriter<File>> = match &config.output {
+        Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("Could not open output file {}: {}", path.display(), e);
 
-            if command == "exit" {
-                exit = true;
+                exit(EXIT_USAGE);
             }
+        },
+        None => None,
+    };
 
-            tx.send(CommandRequest {
-                command: command.to_owned(),
-            })
-            .await
-            .unwrap();
+    if interactive && !config.no_raw && env::var("TERM").is_ok_and(|term| term == "dumb") {
+        warn!("This terminal may not support raw mode; if input looks garbled, try --no-raw");
+    }
+
+    let _terminal: Option<TerminalGuard> = if interactive && !config.no_raw {
+        match setup_terminal() {
+            Ok(guard) => Some(guard),
+            Err(e) => {
+                error!(
+                    "Could not switch the terminal to raw mode: {}; try --no-raw",
+                    e
+                );
+
+                exit(EXIT_USAGE);
+            }
+        }
+    } else {
+        None
+    };
+
+    let addresses: Vec<String> = if !config.addresses.is_empty() {
+        config.addresses.clone()
+    } else if interactive {
+        vec![prompt_address(!config.no_raw)?]
+    } else {
+        error!("--address is required when not running interactively");
+
+        exit(EXIT_USAGE);
+    };
+
+    // Without --public-ip, the IP is looked up through api.ipify.org unless
+    // --no-public-ip keeps the shell from making that third-party request.
+    let public_ip: Arc<PublicIp> = Arc::new(if config.no_public_ip {
+        PublicIp::offline(config.public_ip.clone(), &addresses[0])
+    } else {
+        PublicIp::new(config.public_ip.clone(), &addresses[0])
+    });
+
+    watch_signals();
+
+    // Addresses are tried in order; the first one that accepts the session is
+    // kept, and reconnects go back to it.
+    let mut failures: Vec<String> = Vec::new();
+    let mut refused: usize = 0;
+
+    let attempts = async {
+        for address in &addresses {
+            match LilDbConnection::connect(address, public_ip.clone(), &config.connection).await {
+                Ok((client, response)) if response.success => {
+                    // Batch output is for other programs; only the shell greets.
+                    if interactive {
+                        print_connected(address, &response);
+                    }
+
+                    return Some((address.to_string(), client));
+                }
+                Ok((_, response)) => {
+                    failures.push(format!(
+                        "{} refused the connection: {}",
+                        address, response.message
+                    ));
+
+                    refused += 1;
+                }
+                Err(e) => failures.push(format!("{}: {}", address, e)),
+            }
+        }
+
+        None
+    };
+
+    let raw: bool = interactive && !config.no_raw;
+
+    let Some(connected): Option<Option<(String, LilDbConnection)>> =
+        until_interrupted(attempts, raw).await?
+    else {
+        print!("\n\r");
 
-            tx_disconnect.send(exit).await.unwrap();
+        exit(EXIT_SIGINT);
+    };
+
+    let Some((address, mut client)) = connected else {
+        error!(
+            "Failed to connect to any of {} address(es):",
+            addresses.len()
+        );
+
+        for failure in &failures {
+            error!("  {}", failure);
+        }
+
+        exit(if refused == failures.len() {
+            EXIT_REFUSED
+        } else {
+            EXIT_CONNECT
         });
+    };
 
-        if rx_disconnect.recv().await.unwrap() {
-            let disconnection: DisconnectResponse = client
-                .disconnect_from_db(DisconnectRequest {
-                    ip: public_ip.to_string(),
-                })
-                .await?
-                .into_inner();
+    set_active(&client);
 
-            if disconnection.success {
-                print!("\n\r{}!\n\r", disconnection.message);
+    if !interactive {
+        let success: bool = run_batch(&mut client, &mut transcript, &config).await;
 
-                break;
+        if let Err(e) = client.disconnect().await {
+            warn!("Failed to disconnect: {}", e);
+        }
+
+        if !success {
+            exit(EXIT_COMMAND);
+        }
+
+        return Ok(());
+    }
+
+    if !config.quiet {
+        print_banner(&address);
+    }
+
+    // --no-history starts empty and leaves the history file alone.
+    let mut command_history: Vec<String> = if config.no_history {
+        Vec::new()
+    } else {
+        load_history(&config.history_file)
+    };
+
+    if !config.no_history && trim_history(&mut command_history, config.history_size) {
+        save_history(&config.history_file, &command_history);
+    }
+
+    let mut session: Session = Session {
+        last_output: Vec::new(),
+        started: Instant::now(),
+        commands: 0,
+        received: 0,
+        timings: Vec::new(),
+        transcript,
+    };
+
+    match run_init(&mut client, &mut session, &config).await {
+        Ok(true) => {}
+        Ok(false) => {
+            exit(EXIT_COMMAND);
+        }
+        Err(e) => {
+            print!("{}\n\r", paint(&format!("Init script: {}", e), Tone::Error));
+
+            if config.strict_init {
+                exit(EXIT_COMMAND);
+            }
+        }
+    }
+
+    if let Err(e) = handle_shell(
+        &mut client,
+        &address,
+        &public_ip,
+        &mut command_history,
+        session,
+        &config,
+    )
+    .await
+    {
+        match e.downcast_ref::<Status>() {
+            Some(status) => {
+                for line in status_report("Command failed", status, is_verbose(&config)) {
+                    error!("{}", line);
+                }
             }
+            None => error!("Command failed: {}", e),
+        }
+
+        exit(EXIT_COMMAND);
+    }
+
+    // A --no-raw read given up on by --idle-timeout is still blocked on stdin,
+    // and returning would wait for it while the runtime shuts down.
+    exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lildbsh::lildb::lil_db_shell_server::{LilDbShell, LilDbShellServer};
+    use lildbsh::lildb::{CommandRequest, CommandResponse, ConnectRequest, DisconnectRequest};
+    use std::pin::Pin;
+    use tokio::net::TcpListener;
+    use tokio_stream::Stream;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Streaming};
+
+    fn parse(args: &[&str]) -> Result<Config, UsageError> {
+        parse_with(args, &Environment::default(), ConfigFile::default())
+    }
+
+    fn parse_with(
+        args: &[&str],
+        environment: &Environment,
+        file: ConfigFile,
+    ) -> Result<Config, UsageError> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+        check_args(&args, environment, file)
+    }
+
+    fn invalid(message: &str) -> Option<UsageError> {
+        Some(UsageError::Invalid(message.to_string()))
+    }
+
+    #[test]
+    fn address_short_and_long() {
+        assert_eq!(parse(&["-a", "host:1"]).unwrap().addresses, ["host:1"]);
+        assert_eq!(
+            parse(&["--address", "host:1"]).unwrap().addresses,
+            ["host:1"]
+        );
+    }
+
+    #[test]
+    fn address_list_is_split() {
+        let config: Config = parse(&["-a", "a:1, b:2,"]).unwrap();
+
+        assert_eq!(config.addresses, ["a:1", "b:2"]);
+    }
+
+    #[test]
+    fn address_missing_value() {
+        assert_eq!(
+            parse(&["--address"]).err(),
+            invalid("Missing value for --address")
+        );
+        assert_eq!(
+            parse(&["--tls", "-a"]).err(),
+            invalid("Missing value for -a")
+        );
+    }
+
+    #[test]
+    fn address_empty_value() {
+        assert_eq!(
+            parse(&["-a", " , "]).err(),
+            invalid("--address cannot be empty")
+        );
+    }
+
+    #[test]
+    fn repeated_address_adds_fallbacks() {
+        let config: Config = parse(&["-a", "a:1", "--address", "b:2"]).unwrap();
+
+        assert_eq!(config.addresses, ["a:1", "b:2"]);
+    }
+
+    #[test]
+    fn positional_address() {
+        assert_eq!(parse(&["host:1"]).unwrap().addresses, ["host:1"]);
+        assert_eq!(
+            parse(&["host:1", "-a", "other:2"]).err(),
+            invalid("Give the address either as an argument or with --address, not both")
+        );
+        assert_eq!(
+            parse(&["host:1", "other:2"]).err(),
+            invalid("Unknown argument: other:2")
+        );
+    }
+
+    #[test]
+    fn address_precedence() {
+        let environment: Environment = Environment {
+            address: Some("env:1".to_string()),
+            ..Environment::default()
+        };
+        let file = || ConfigFile {
+            address: Some("file:1".to_string()),
+            ..ConfigFile::default()
+        };
+
+        assert_eq!(
+            parse_with(&[], &Environment::default(), file())
+                .unwrap()
+                .addresses,
+            ["file:1"]
+        );
+        assert_eq!(
+            parse_with(&[], &environment, file()).unwrap().addresses,
+            ["env:1"]
+        );
+        assert_eq!(
+            parse_with(&["-a", "flag:1"], &environment, file())
+                .unwrap()
+                .addresses,
+            ["flag:1"]
+        );
+    }
+
+    #[test]
+    fn config_missing_value() {
+        let args: Vec<String> = vec!["--tls".to_string(), "--config".to_string()];
+
+        assert_eq!(
+            read_config_file(&args).err(),
+            invalid("Missing value for --config")
+        );
+    }
+
+    #[test]
+    fn unknown_flag() {
+        assert_eq!(
+            parse(&["--bogus"]).err(),
+            invalid("Unknown argument: --bogus")
+        );
+        assert_eq!(parse(&["-x"]).err(), invalid("Unknown argument: -x"));
+    }
+
+    #[test]
+    fn invalid_value() {
+        assert_eq!(
+            parse(&["--retries", "many"]).err(),
+            invalid("Invalid value for --retries: many")
+        );
+        assert_eq!(
+            parse(&["--format", "xml"]).err(),
+            invalid("Invalid value for --format: xml")
+        );
+    }
+
+    #[test]
+    fn help_short_and_long() {
+        assert_eq!(parse(&["-h"]).err(), Some(UsageError::Help));
+        assert_eq!(parse(&["--help"]).err(), Some(UsageError::Help));
+        assert_eq!(
+            parse(&["--tls", "--help", "--bogus"]).err(),
+            Some(UsageError::Help)
+        );
+    }
+
+    #[test]
+    fn version_short_and_long() {
+        assert_eq!(parse(&["-V"]).err(), Some(UsageError::Version));
+        assert_eq!(parse(&["--version"]).err(), Some(UsageError::Version));
+    }
+
+    #[test]
+    fn combined_flags() {
+        let config: Config = parse(&[
+            "-a",
+            "host:1",
+            "--tls",
+            "--retries",
+            "5",
+            "-t",
+            "--format",
+            "csv",
+            "--no-raw",
+            "--pager",
+            "always",
+            "-q",
+        ])
+        .unwrap();
+
+        assert_eq!(config.addresses, ["host:1"]);
+        assert!(config.connection.tls);
+        assert_eq!(config.connection.retries, 5);
+        assert!(config.timing);
+        assert!(matches!(config.format, Format::Csv));
+        assert!(config.quiet);
+        assert_eq!(config.log_level, Level::WARN);
+        // The pager needs raw mode, so --no-raw turns it off.
+        assert!(matches!(config.pager, Pager::Off));
+    }
+
+    #[test]
+    fn later_flag_wins() {
+        let config: Config = parse(&["--retries", "1", "--retries", "2"]).unwrap();
+
+        assert_eq!(config.connection.retries, 2);
+    }
+
+    #[test]
+    fn command_after_double_dash() {
+        let config: Config = parse(&["-a", "host:1", "--", "SELECT", "--tls"]).unwrap();
+
+        assert_eq!(config.command.as_deref(), Some("SELECT --tls"));
+        assert!(!config.connection.tls);
+        assert_eq!(parse(&["--"]).err(), invalid("Missing command after --"));
+        assert_eq!(
+            parse(&["-c", "a", "--", "b"]).err(),
+            invalid("Give the command either with --command or after --, not both")
+        );
+    }
+
+    fn redirect(command: &str) -> Option<(&str, String, bool)> {
+        let (rest, redirect) = split_redirect(command);
+
+        redirect.map(|redirect| (rest, redirect.path, redirect.append))
+    }
+
+    #[test]
+    fn redirect_to_file() {
+        assert_eq!(
+            redirect("SELECT 1 > out.txt"),
+            Some(("SELECT 1", "out.txt".to_string(), false))
+        );
+        assert_eq!(
+            redirect("SELECT 1 >> logs/out"),
+            Some(("SELECT 1", "logs/out".to_string(), true))
+        );
+        assert_eq!(
+            redirect("SELECT 1 >./out"),
+            Some(("SELECT 1", "./out".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn forced_redirect_takes_any_name() {
+        assert_eq!(
+            redirect("SELECT 1 >| out"),
+            Some(("SELECT 1", "out".to_string(), false))
+        );
+        assert_eq!(
+            redirect("SELECT 1 >>| report.xml"),
+            Some(("SELECT 1", "report.xml".to_string(), true))
+        );
+        assert!(redirect("SELECT 1 > out").is_none());
+        assert!(redirect("SELECT 1 >|").is_none());
+    }
+
+    #[test]
+    fn comparisons_are_not_redirects() {
+        for command in [
+            "SELECT * FROM t WHERE age > 30",
+            "SELECT x>=5",
+            "SELECT x >= 5",
+            "SELECT x > =5",
+            "SELECT * FROM a WHERE a.id > b.id",
+            "SELECT a>b",
+            "SELECT 1>out.txt",
+            "SELECT 2.5 > 1.5",
+        ] {
+            assert!(redirect(command).is_none(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn quoted_redirects_are_sent() {
+        assert!(redirect("SELECT \"a > b.txt\"").is_none());
+        assert!(redirect("SELECT 'a >> b.txt").is_none());
+        assert!(redirect("> out.txt").is_none());
+    }
+
+    fn history_config(path: &Path, extra: &[&str]) -> Config {
+        let mut args: Vec<&str> = vec!["--history-file", path.to_str().unwrap()];
+
+        args.extend(extra);
+
+        parse(&args).unwrap()
+    }
+
+    fn temp_history(name: &str, contents: &str) -> PathBuf {
+        let path: PathBuf = env::temp_dir().join(format!("lildbsh-{}-{}", name, process::id()));
+
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn kept_history_file_survives_rewrites() {
+        let path: PathBuf = temp_history("kept", "old1\nold2\nold3\n");
+        let config: Config = history_config(&path, &["--history-dedup-all"]);
+
+        // .clearhistory answered no: memory is empty, the file is kept.
+        let mut history: Vec<String> = Vec::new();
+
+        record_history(&mut history, "abc", true, &config);
+        record_history(&mut history, "old2", true, &config);
+
+        assert_eq!(history, ["abc", "old2"]);
+        assert_eq!(load_history(&path), ["old1", "old3", "abc", "old2"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn kept_history_file_is_trimmed() {
+        let path: PathBuf = temp_history("trimmed", "old1\nold2\nold3\n");
+        let config: Config = history_config(&path, &["--history-size", "3"]);
+        let mut history: Vec<String> = Vec::new();
+
+        record_history(&mut history, "abc", true, &config);
+
+        assert_eq!(load_history(&path), ["old2", "old3", "abc"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn no_history_records_nothing() {
+        let path: PathBuf = temp_history("none", "old1\n");
+        let config: Config = history_config(&path, &["--no-history"]);
+        let mut history: Vec<String> = Vec::new();
+
+        record_history(&mut history, "abc", false, &config);
+
+        assert!(history.is_empty());
+        assert_eq!(load_history(&path), ["old1"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn boundaries_step_over_whole_graphemes() {
+        // A precomposed é, an e with a combining accent, a CJK character, a
+        // thumbs up with a skin tone and a flag.
+        let input: &str = "h\u{e9}e\u{301}\u{65e5}\u{1f44d}\u{1f3fd}\u{1f1eb}\u{1f1f7}";
+        let stops: [usize; 7] = [0, 1, 3, 6, 9, 17, 25];
+
+        for pair in stops.windows(2) {
+            assert_eq!(next_boundary(input, pair[0]), pair[1]);
+            assert_eq!(prev_boundary(input, pair[1]), pair[0]);
         }
 
-        let response: Response<Streaming<CommandResponse>> =
-            client.run_command(ReceiverStream::new(rx)).await?;
+        assert_eq!(next_boundary(input, input.len()), input.len());
+        assert_eq!(prev_boundary(input, 0), 0);
+    }
+
+    #[test]
+    fn layout_counts_wide_characters_twice() {
+        let input: &str = "\u{65e5}\u{672c}";
+
+        assert_eq!(
+            layout(">> ", input, input.len(), 80),
+            Layout {
+                end_row: 0,
+                cursor_row: 0,
+                column: 7,
+                pending_wrap: false,
+            }
+        );
+        assert_eq!(layout(">> ", input, 3, 80).column, 5);
+        assert_eq!(layout(">> ", "\u{1f44d}\u{1f3fd}x", 8, 80).column, 5);
+        assert_eq!(layout(">> ", "e\u{301}x", 3, 80).column, 4);
+    }
 
-        let mut inbound: Streaming<CommandResponse> = response.into_inner();
+    #[test]
+    fn layout_wraps_wide_characters() {
+        let input: &str = "\u{65e5}\u{672c}\u{8a9e}";
 
-        while let Some(res) = inbound.message().await? {
-            print!("\n\r{}\n\r", res.output);
+        assert_eq!(
+            layout(">> ", input, input.len(), 5),
+            Layout {
+                end_row: 1,
+                cursor_row: 1,
+                column: 4,
+                pending_wrap: false,
+            }
+        );
 
-            if res.output.is_empty() {
-                process::exit(0);
+        // ">> " plus two wide characters fills 7 columns exactly.
+        assert_eq!(
+            layout(">> ", "\u{65e5}\u{672c}", 6, 7),
+            Layout {
+                end_row: 1,
+                cursor_row: 1,
+                column: 0,
+                pending_wrap: true,
             }
+        );
+    }
+
+    #[test]
+    fn layout_spans_continuation_lines() {
+        let input: &str = "\u{65e5}\n\u{672c}";
+        let continuation: usize = CONTINUATION_PROMPT.width();
+
+        let end: Layout = layout(">> ", input, input.len(), 80);
+
+        assert_eq!((end.end_row, end.cursor_row), (1, 1));
+        assert_eq!(end.column, continuation + 2);
+        assert_eq!(layout(">> ", input, 3, 80).cursor_row, 0);
+    }
+
+    #[test]
+    fn exit_and_meta_commands_are_not_remote() {
+        // Only Input::Remote is recorded in history.
+        assert_eq!(classify("exit".to_string(), false), Input::Exit);
+        assert_eq!(classify(".exit".to_string(), false), Input::Exit);
+        assert_eq!(classify("SELECT".to_string(), true), Input::Exit);
+        assert_eq!(classify(".ping".to_string(), false), Input::Ping);
+        assert_eq!(
+            classify(".clearhistory".to_string(), false),
+            Input::ClearHistory
+        );
+        assert_eq!(
+            classify(".connect host:1".to_string(), false),
+            Input::Connect("host:1".to_string())
+        );
+        assert_eq!(
+            classify(".history".to_string(), false),
+            Input::Meta(".history".to_string())
+        );
+        assert_eq!(
+            classify("SELECT 1".to_string(), false),
+            Input::Remote("SELECT 1".to_string())
+        );
+    }
+
+    #[test]
+    fn history_skips_repeats_and_blank_commands() {
+        let mut history: Vec<String> = vec!["a".to_string(), "b".to_string()];
+
+        assert!(!push_history(&mut history, "b", false));
+        assert!(!push_history(&mut history, "  ", false));
+        assert!(push_history(&mut history, "a", true));
+        assert_eq!(history, ["b", "a"]);
+    }
+
+    fn entries(list: &[&str]) -> Vec<String> {
+        list.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn recall_steps_through_history_and_back_to_draft() {
+        let history: Vec<String> = entries(&["a", "b"]);
+        let mut recall: Recall = Recall::new(&history);
+
+        assert_eq!(recall.down(&history), None);
+        assert_eq!(recall.up(&history, "typed").as_deref(), Some("b"));
+        assert_eq!(recall.up(&history, "b").as_deref(), Some("a"));
+        assert_eq!(recall.up(&history, "a"), None);
+        assert_eq!(recall.down(&history).as_deref(), Some("b"));
+        assert_eq!(recall.down(&history).as_deref(), Some("typed"));
+        assert_eq!(recall.down(&history), None);
+    }
+
+    #[test]
+    fn empty_enter_resets_recall() {
+        let history: Vec<String> = entries(&["a", "b"]);
+        let mut recall: Recall = Recall::new(&history);
+
+        // Up to "b", erase it all, then Enter on the empty line.
+        assert_eq!(recall.up(&history, "").as_deref(), Some("b"));
+
+        recall.reset(&history);
+
+        // Up starts again from the newest entry, and Down returns to an
+        // empty line rather than the erased one.
+        assert_eq!(recall.up(&history, "").as_deref(), Some("b"));
+        assert_eq!(recall.down(&history).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn positional_address_among_flags() {
+        let config: Config = parse(&["--tls", "host:1", "--retries", "2"]).unwrap();
+
+        assert_eq!(config.addresses, ["host:1"]);
+        assert!(config.connection.tls);
+        assert_eq!(config.connection.retries, 2);
+
+        // A value taken by a flag is not the positional address.
+        assert!(parse(&["--prompt", "db> "]).unwrap().addresses.is_empty());
+    }
+
+    #[test]
+    fn positional_address_overrides_env_and_file() {
+        let environment: Environment = Environment {
+            address: Some("env:1".to_string()),
+            ..Environment::default()
+        };
+        let file: ConfigFile = ConfigFile {
+            address: Some("file:1".to_string()),
+            ..ConfigFile::default()
+        };
+
+        let config: Config = parse_with(&["a:1,b:2"], &environment, file).unwrap();
+
+        assert_eq!(config.addresses, ["a:1", "b:2"]);
+    }
+
+    #[test]
+    fn positional_conflicts_with_address_in_either_order() {
+        let conflict =
+            invalid("Give the address either as an argument or with --address, not both");
+
+        assert_eq!(parse(&["-a", "other:2", "host:1"]).err(), conflict);
+        assert_eq!(parse(&["host:1", "--address", "other:2"]).err(), conflict);
+    }
+
+    struct FailsAfterOne;
+
+    #[tonic::async_trait]
+    impl LilDbShell for FailsAfterOne {
+        type RunCommandStream = Pin<Box<dyn Stream<Item = Result<CommandResponse, Status>> + Send>>;
+
+        async fn run_command(
+            &self,
+            _request: Request<Streaming<CommandRequest>>,
+        ) -> Result<Response<Self::RunCommandStream>, Status> {
+            let replies: Vec<Result<CommandResponse, Status>> = vec![
+                Ok(CommandResponse {
+                    output: "partial".to_string(),
+                    raw_output: Vec::new(),
+                }),
+                Err(Status::internal("boom")),
+            ];
+
+            Ok(Response::new(Box::pin(tokio_stream::iter(replies))))
+        }
+
+        async fn connect_to_db(
+            &self,
+            _request: Request<ConnectRequest>,
+        ) -> Result<Response<ConnectResponse>, Status> {
+            Ok(Response::new(ConnectResponse {
+                success: true,
+                message: String::new(),
+                server_version: String::new(),
+            }))
+        }
+
+        async fn disconnect_from_db(
+            &self,
+            _request: Request<DisconnectRequest>,
+        ) -> Result<Response<DisconnectResponse>, Status> {
+            Ok(Response::new(DisconnectResponse {
+                success: true,
+                message: String::new(),
+            }))
         }
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn stream_error_keeps_received_output() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        let incoming: TcpIncoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(LilDbShellServer::new(FailsAfterOne))
+                .serve_with_incoming(incoming),
+        );
+
+        let public_ip: Arc<PublicIp> = Arc::new(PublicIp::offline(None, &address));
+        let (mut client, _): (LilDbConnection, ConnectResponse) =
+            LilDbConnection::connect(&address, public_ip, &ConnectOptions::default())
+                .await
+                .unwrap();
+
+        let config: Config = parse(&["--no-raw", "--no-spinner"]).unwrap();
+        let mut session: Session = Session {
+            last_output: Vec::new(),
+            transcript: None,
+            started: Instant::now(),
+            commands: 0,
+            received: 0,
+            timings: Vec::new(),
+        };
+
+        // The error ends the command, not the shell.
+        let succeeded: bool = run_remote(&mut client, "select", &mut session, None, false, &config)
+            .await
+            .unwrap();
+
+        assert!(!succeeded);
+
+        assert_eq!(session.last_output, ["partial"]);
+        assert_eq!(session.timings.len(), 1);
+    }
+
+    #[test]
+    fn whitespace_is_not_dispatched() {
+        for command in ["", " ", "\t", "\n\n", "  \r\n  "] {
+            assert!(is_blank(command), "{:?}", command);
+            assert!(is_blank(&normalize_command(command)), "{:?}", command);
+        }
+
+        assert!(!is_blank(" ping "));
+        assert!(!is_blank("\nselect\n"));
+    }
+
+    #[test]
+    fn raw_newlines_return_to_column_one() {
+        assert_eq!(raw_newlines(b"one\ntwo\n"), b"one\r\ntwo\r\n");
+        assert_eq!(raw_newlines(b"\nkept\r\n"), b"\r\nkept\r\n");
+        assert_eq!(raw_newlines(b"none"), b"none");
+    }
+
+    #[test]
+    fn unknown_width_falls_back_to_80() {
+        assert_eq!(terminal_columns(Ok((120, 40))), 120);
+        assert_eq!(terminal_columns(Ok((0, 0))), 80);
+        assert_eq!(terminal_columns(Err(io::Error::other("no tty"))), 80);
+    }
+
+    #[test]
+    fn every_chunk_starts_a_numbered_line() {
+        let mut numbers: LineNumbers = LineNumbers::new();
+
+        assert_eq!(numbers.number("row 1"), "   1  row 1");
+        assert_eq!(numbers.number("row 2"), "   2  row 2");
+        assert_eq!(
+            numbers.number("row 3\nrow 4\n"),
+            "   3  row 3\n   4  row 4\n"
+        );
+    }
+
+    #[test]
+    fn csv_covers_the_whole_reply() {
+        let chunks: Vec<String> = vec!["id\tname".to_string(), "1\tAda, L.".to_string()];
+
+        assert_eq!(csv_document(&chunks), "id,name\r\n1,\"Ada, L.\"\r\n");
+        assert_eq!(csv_document(&[]), "");
+    }
+
+    #[test]
+    fn broken_config_still_allows_help() {
+        let path: PathBuf = env::temp_dir().join(format!("lildbsh-config-{}.toml", process::id()));
+
+        fs::write(&path, "retry_dealy = 5\n").unwrap();
+
+        let load = |extra: &[&str]| {
+            let mut args: Vec<String> = vec!["--config".to_string(), path.display().to_string()];
+
+            args.extend(extra.iter().map(|arg| arg.to_string()));
+
+            load_config(&args, &Environment::default())
+        };
+
+        assert_eq!(load(&["--help"]).err(), Some(UsageError::Help));
+        assert_eq!(load(&["-V"]).err(), Some(UsageError::Version));
+        assert!(matches!(
+            load(&["--generate-completions", "bash"]),
+            Err(UsageError::Completions(_))
+        ));
+        assert!(matches!(
+            load(&["-a", "host:1"]),
+            Err(UsageError::Invalid(message)) if message.contains("retry_dealy")
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
 }